@@ -0,0 +1,387 @@
+use crate::comments::Comment;
+use crate::error::{Error, Result};
+use crate::runner::{self, takes_operand, Command, Program, Word};
+use crate::transform;
+use std::collections::BTreeMap;
+
+fn skips_back(command: &Command) -> bool {
+    matches!(command, Command::SKIP_BACK_ZERO | Command::SKIP_BACK_NONZERO)
+}
+
+/// The score a mnemonic stands for. NOP stands for several, so needs its score as an operand.
+fn command_score(mnemonic: &str) -> Option<u8> {
+    (5..=17).find(|score| format!("{:?}", runner::action(*score)) == mnemonic)
+}
+
+/// Index of the word a skip carries on from when it's taken, if that's in or just after the program
+fn skip_target(words: &[Word], pc: usize) -> Option<usize> {
+    let distance = words.get(pc + 1)?.score as usize;
+    let target = if skips_back(&runner::action(words[pc].score)) {
+        (pc + 1).checked_sub(distance)?
+    } else {
+        pc + 2 + distance
+    };
+    if target <= words.len() {
+        Some(target)
+    } else {
+        None
+    }
+}
+
+/// Writes a program as assembly, one instruction per line with its operand. Skips name the label they
+/// land on, where that's the start of an instruction, and comments say what each PUSH is as a character
+/// and what a skip back runs when it isn't taken.
+pub fn disassemble(program: &Program) -> String {
+    let words = &program.words;
+    let operands = transform::operand_slots(words);
+    let mut labels = BTreeMap::new();
+    for pc in 0..words.len() {
+        let command = runner::action(words[pc].score);
+        if operands[pc] || command == Command::PUSH || !takes_operand(&command) {
+            continue;
+        }
+        // Skips into the middle of an instruction keep their distance
+        if let Some(target) = skip_target(words, pc).filter(|target| !operands.get(*target).unwrap_or(&false))
+        {
+            labels.insert(target, String::new());
+        }
+    }
+    for (index, name) in labels.values_mut().enumerate() {
+        *name = format!("L{}", index + 1);
+    }
+
+    let mut out = String::new();
+    let mut comments = program.comments.iter().peekable();
+    for pc in 0..=words.len() {
+        if operands.get(pc) == Some(&true) {
+            continue;
+        }
+        while let Some(comment) = comments.next_if(|comment| comment.position <= pc) {
+            for line in comment.text.lines() {
+                out += &format!("; {}\n", line.trim());
+            }
+        }
+        let label = labels
+            .get(&pc)
+            .map(|name| format!("{}:", name))
+            .unwrap_or_default();
+        let word = match words.get(pc) {
+            Some(word) => word,
+            None => {
+                if !label.is_empty() {
+                    out += &format!("{}\n", label);
+                }
+                break;
+            }
+        };
+        let command = runner::action(word.score);
+        let mut line = format!("{:<8}{:?}", label, command);
+        let mut note = String::new();
+        if command == Command::NOP {
+            line += &format!(" {}", word.score);
+        } else if let Some(operand) = words.get(pc + 1).filter(|_| takes_operand(&command)) {
+            let value = operand.score;
+            match (
+                &command,
+                skip_target(words, pc).and_then(|target| labels.get(&target)),
+            ) {
+                (Command::PUSH, _) => {
+                    line += &format!(" {}", value);
+                    let c = value as char;
+                    if c.is_ascii_graphic() || c.is_ascii_whitespace() {
+                        note = format!("{:?}", c);
+                    }
+                }
+                (_, Some(name)) => {
+                    line += &format!(" {}", name);
+                    note = value.to_string();
+                }
+                (_, None) => line += &format!(" {}", value),
+            }
+            if skips_back(&command) && !runner::is_nop(value) {
+                note += &format!(", runs {:?} if not taken", runner::action(value));
+            }
+        }
+        if note.is_empty() {
+            out += &format!("{}\n", line);
+        } else {
+            out += &format!("{:<32}; {}\n", line, note.trim_start_matches(", "));
+        }
+    }
+    out
+}
+
+fn parse_error(number: usize, msg: &str) -> Error {
+    Error::Parse(format!("Line {}: {}", number, msg))
+}
+
+/// One line of assembly. The comment is only kept for lines with nothing else on them.
+struct Line<'a> {
+    number: usize,
+    label: Option<&'a str>,
+    mnemonic: Option<&'a str>,
+    operand: Option<&'a str>,
+    comment: Option<&'a str>,
+}
+
+/// A character in single quotes at the start of some text, which may itself be a space, ';' or quote
+fn quoted(text: &str) -> Option<&str> {
+    let mut chars = text.char_indices();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some((_, '\'')), Some(_), Some((end, '\''))) => Some(&text[..end + 1]),
+        _ => None,
+    }
+}
+
+/// Splits a line into fields and any comment after a ';', keeping quoted characters whole
+fn split_line(text: &str) -> (Vec<&str>, Option<&str>) {
+    let mut fields = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        if let Some(field) = quoted(rest) {
+            fields.push(field);
+            rest = &rest[field.len()..];
+        } else if let Some(comment) = rest.strip_prefix(';') {
+            return (fields, Some(comment.trim()));
+        } else {
+            let end = rest
+                .find(|c: char| c.is_whitespace() || c == ';')
+                .unwrap_or(rest.len());
+            fields.push(&rest[..end]);
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    (fields, None)
+}
+
+fn parse_line(number: usize, text: &str) -> Result<Line<'_>> {
+    let (fields, comment) = split_line(text);
+    let mut fields = fields.into_iter();
+    let mut first = fields.next();
+    let label = match first.and_then(|field| field.strip_suffix(':')) {
+        Some(label) => {
+            first = fields.next();
+            Some(label)
+        }
+        None => None,
+    };
+    let operand = fields.next();
+    if let Some(extra) = fields.next() {
+        return Err(parse_error(number, &format!("Unexpected '{}'", extra)));
+    }
+    Ok(Line {
+        number,
+        label,
+        mnemonic: first,
+        operand,
+        comment: if first.is_none() && label.is_none() {
+            comment
+        } else {
+            None
+        },
+    })
+}
+
+/// A number, or a character in single quotes
+fn operand_value(number: usize, operand: &str) -> Result<u8> {
+    let mut chars = operand.chars();
+    if let (Some('\''), Some(c), Some('\''), None) = (chars.next(), chars.next(), chars.next(), chars.next())
+    {
+        if c.is_ascii() {
+            return Ok(c as u8);
+        }
+    }
+    operand.parse().map_err(|_| {
+        parse_error(
+            number,
+            &format!("'{}' isn't a label or a number from 0 to 255", operand),
+        )
+    })
+}
+
+/// Turns assembly back into a program. Skips can name a label rather than giving their distance.
+pub fn assemble(text: &str) -> Result<Program> {
+    let lines = text
+        .lines()
+        .enumerate()
+        .map(|(index, line)| parse_line(index + 1, line))
+        .collect::<Result<Vec<Line>>>()?;
+
+    // Where each label is, and where each instruction's words start
+    let mut labels = BTreeMap::new();
+    let mut starts = Vec::new();
+    let mut length = 0;
+    for (index, line) in lines.iter().enumerate() {
+        if let Some(label) = line.label {
+            if labels.insert(label, length).is_some() {
+                return Err(parse_error(
+                    line.number,
+                    &format!("Label '{}' is used twice", label),
+                ));
+            }
+        }
+        starts.push(length);
+        if let Some(mnemonic) = line.mnemonic {
+            let score = command_score(mnemonic);
+            if score.is_none() && mnemonic != "NOP" {
+                return Err(parse_error(
+                    line.number,
+                    &format!("Unknown command '{}'", mnemonic),
+                ));
+            }
+            let last = lines[index + 1..].iter().all(|line| line.mnemonic.is_none());
+            let operand = score.is_some_and(|score| takes_operand(&runner::action(score)));
+            if (operand || mnemonic == "NOP") && line.operand.is_none() && !(operand && last) {
+                return Err(parse_error(
+                    line.number,
+                    &format!("{} needs an operand", mnemonic),
+                ));
+            }
+            if !operand && mnemonic != "NOP" && line.operand.is_some() {
+                return Err(parse_error(
+                    line.number,
+                    &format!("{} doesn't take an operand", mnemonic),
+                ));
+            }
+            length += if operand && line.operand.is_some() { 2 } else { 1 };
+        }
+    }
+
+    let mut program = Program::default();
+    for (line, start) in lines.iter().zip(starts) {
+        if let Some(comment) = line.comment {
+            program.comments.push(Comment {
+                position: start,
+                text: String::from(comment),
+            });
+        }
+        let mnemonic = match line.mnemonic {
+            Some(mnemonic) => mnemonic,
+            None => continue,
+        };
+        let word = |score| Word {
+            word: String::new(),
+            score,
+        };
+        let score = match command_score(mnemonic) {
+            Some(score) => score,
+            None => {
+                let score = operand_value(line.number, line.operand.unwrap_or_default())?;
+                if !runner::is_nop(score) {
+                    return Err(parse_error(line.number, &format!("{} isn't a NOP score", score)));
+                }
+                score
+            }
+        };
+        program.words.push(word(score));
+        let command = runner::action(score);
+        if command == Command::NOP || !takes_operand(&command) {
+            continue;
+        }
+        let operand = match line.operand {
+            Some(operand) => operand,
+            None => continue,
+        };
+        let value = match labels.get(operand) {
+            Some(&target) if command != Command::PUSH => {
+                let distance = if skips_back(&command) {
+                    (start + 1).checked_sub(target)
+                } else {
+                    target.checked_sub(start + 2)
+                };
+                match distance.filter(|distance| *distance <= 255) {
+                    Some(distance) => distance as u8,
+                    None => {
+                        return Err(parse_error(
+                            line.number,
+                            &format!("Can't skip from here to '{}'", operand),
+                        ))
+                    }
+                }
+            }
+            _ => operand_value(line.number, operand)?,
+        };
+        program.words.push(word(value));
+    }
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assemble, disassemble};
+    use crate::runner::{self, Program};
+
+    fn scores(program: &Program) -> Vec<u8> {
+        program.words.iter().map(|word| word.score).collect()
+    }
+
+    /// A loop back to the DUP, then a skip to the end
+    const COUNTDOWN: &str = "[5:PUSH]\n[3]\n[12:DUP]\n[9:OUTPUT]\n[5:PUSH]\n[1]\n[10:SUBTRACT]\n[1]\n[12:DUP]\n\
+                             [16:SKIP_BACK_NONZERO]\n[8]\n[13:SKIP_AHEAD_ZERO]\n[3]\n[5:PUSH]\n[72]\n[9:OUTPUT]\n";
+
+    #[test]
+    fn test_disassemble() {
+        let program = Program {
+            words: runner::get_wottas(COUNTDOWN).unwrap(),
+            ..Program::default()
+        };
+        let listing = disassemble(&program);
+        let expected = [
+            "        PUSH 3",
+            "L1:     DUP",
+            "        OUTPUT",
+            "        PUSH 1",
+            "        SUBTRACT",
+            "        NOP 1",
+            "        DUP",
+            "        SKIP_BACK_NONZERO L1    ; 8, runs INPUT if not taken",
+            "        SKIP_AHEAD_ZERO L2      ; 3",
+            "        PUSH 72                 ; 'H'",
+            "        OUTPUT",
+            "L2:",
+        ];
+        assert_eq!(listing, expected.join("\n") + "\n");
+        assert_eq!(scores(&assemble(&listing).unwrap()), scores(&program));
+    }
+
+    #[test]
+    fn test_assemble() {
+        let err = assemble("start: PUSH 'H' ; a comment\n  OUTPUT\n  NOP 20\n\n  SKIP_AHEAD_ZERO start\n")
+            .unwrap_err();
+        assert!(err.to_string().contains("Line 5"), "{}", err);
+        let program = assemble("; Say H\nstart: PUSH 'H'\n  OUTPUT\n  NOP 20\n  PUSH").unwrap();
+        assert_eq!(scores(&program), vec![5, 72, 9, 20, 5]);
+        assert_eq!(program.comments[0].text, "Say H");
+        assert!(assemble("PUSH\nOUTPUT").is_err());
+        assert!(assemble("NOP 5").is_err());
+        assert!(assemble("JUMP 5").is_err());
+    }
+
+    #[test]
+    fn test_quoted_characters() {
+        let program = assemble("PUSH ';' ; semicolon\nPUSH ' '\nPUSH '''\nPUSH ';';\n").unwrap();
+        assert_eq!(scores(&program), vec![5, b';', 5, b' ', 5, b'\'', 5, b';']);
+        assert_eq!(
+            scores(&assemble(&disassemble(&program)).unwrap()),
+            scores(&program)
+        );
+        assert!(assemble("PUSH ' ' 1").is_err());
+    }
+
+    #[test]
+    fn test_examples_reassemble() {
+        for example in &["alphabet", "aunts", "hello-world", "hello-world-hamlet", "hi"] {
+            let words = runner::get_words_fn(&format!("examples/{}.beatnik", example)).unwrap();
+            let program = Program {
+                words,
+                ..Program::default()
+            };
+            let listing = disassemble(&program);
+            let reassembled = assemble(&listing).unwrap();
+            assert_eq!(scores(&reassembled), scores(&program), "{}", example);
+            // Only the scores come back, but that's all the listing shows, so it's the same again
+            assert_eq!(disassemble(&reassembled), listing, "{}", example);
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use crate::error::{Error, Result};
+use crate::runner;
+
+/// What starts and ends a comment in Beatnik source. Every alphabetic run is normally an instruction, so
+/// comments are only skipped when the source starts with front matter turning them on, like this:
+///
+/// ```text
+/// ---
+/// comments: "(* *)"
+/// ---
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delimiters {
+    pub open: String,
+    pub close: String,
+}
+
+impl Default for Delimiters {
+    fn default() -> Delimiters {
+        Delimiters {
+            open: String::from("(*"),
+            close: String::from("*)"),
+        }
+    }
+}
+
+/// A comment in a program, with how many words come before it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    pub position: usize,
+    pub text: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FrontMatter {
+    /// Opening and closing delimiters, separated by whitespace
+    comments: String,
+}
+
+impl Delimiters {
+    /// Front matter that turns on comments with these delimiters
+    pub fn front_matter(self: &Delimiters) -> String {
+        format!(
+            "---\ncomments: {:?}\n---\n",
+            format!("{} {}", self.open, self.close)
+        )
+    }
+}
+
+/// If some source starts with front matter turning comments on, gives the delimiters and the source after
+/// it. Anything else that looks like front matter is just part of the program.
+pub(crate) fn front_matter(source: &str) -> Option<(Delimiters, &str)> {
+    let body = source
+        .strip_prefix("---\n")
+        .or_else(|| source.strip_prefix("---\r\n"))?;
+    let mut length = 0;
+    for line in body.split_inclusive('\n') {
+        if line.trim_end() == "---" {
+            let header: FrontMatter = serde_yaml::from_str(&body[..length]).ok()?;
+            let mut delimiters = header.comments.split_whitespace();
+            return match (delimiters.next(), delimiters.next(), delimiters.next()) {
+                (Some(open), Some(close), None) => Some((
+                    Delimiters {
+                        open: String::from(open),
+                        close: String::from(close),
+                    },
+                    &body[length + line.len()..],
+                )),
+                _ => None,
+            };
+        }
+        length += line.len();
+    }
+    None
+}
+
+/// Puts comments into generated Beatnik prose in front of the words they came before, along with the front
+/// matter turning them on
+pub fn annotate(prose: &str, comments: &[Comment], delimiters: &Delimiters) -> Result<String> {
+    if comments.is_empty() {
+        return Ok(String::from(prose));
+    }
+    if prose.contains(&delimiters.open) {
+        return Err(Error::Generate(format!(
+            "Generated text already contains '{}', so can't have comments",
+            delimiters.open
+        )));
+    }
+    let offsets = runner::word_offsets(prose)?;
+    let mut out = delimiters.front_matter();
+    let mut copied = 0;
+    for comment in comments {
+        if comment.text.contains(&delimiters.close) {
+            return Err(Error::Generate(format!(
+                "Comment '{}' contains '{}'",
+                comment.text, delimiters.close
+            )));
+        }
+        let at = offsets.get(comment.position).cloned().unwrap_or(prose.len());
+        out += &prose[copied..at];
+        if !out.is_empty() && !out.ends_with(char::is_whitespace) {
+            out.push(' ');
+        }
+        out += &format!("{} {} {}", delimiters.open, comment.text, delimiters.close);
+        out.push(if at < prose.len() { ' ' } else { '\n' });
+        copied = at;
+    }
+    out += &prose[copied..];
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{annotate, front_matter, Comment, Delimiters};
+    use crate::runner::{self, get_words, get_wottas};
+
+    #[test]
+    fn test_front_matter() {
+        let source = "---\ncomments: \"{{ }}\"\n---\nHello";
+        let (delimiters, rest) = front_matter(source).unwrap();
+        assert_eq!(delimiters.open, "{{");
+        assert_eq!(delimiters.close, "}}");
+        assert_eq!(rest, "Hello");
+        assert_eq!(
+            front_matter(&Delimiters::default().front_matter()).unwrap().0,
+            Delimiters::default()
+        );
+        assert!(front_matter("---\ntitle: Hello\n---\nHello").is_none());
+        assert!(front_matter("---\nHello").is_none());
+    }
+
+    #[test]
+    fn test_skips_comments() {
+        let source = "---\ncomments: \"(* *)\"\n---\nHello (* Trace the dog,\nand cat *) aunts!";
+        let words: Vec<String> = get_words(source).unwrap().into_iter().map(|w| w.word).collect();
+        assert_eq!(words, vec!["Hello", "aunts"]);
+        assert!(get_words("---\ncomments: \"(* *)\"\n---\nHello (* aunts").is_err());
+        // Without the front matter, it's all words
+        assert_eq!(get_words("Hello (* Trace *) aunts").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_long_comments() {
+        let source = format!(
+            "---\ncomments: \"(* *)\"\n---\nHello (*\n{}*) aunts!\n",
+            "Trace the dog\n".repeat(10_000)
+        );
+        let program = runner::read_words_with_comments(Box::new(source.as_bytes()), "test", false).unwrap();
+        assert_eq!(program.words.len(), 2);
+        assert_eq!(program.comments.len(), 1);
+        assert_eq!(program.comments[0].position, 1);
+        let unfinished = &source[..source.len() - 10];
+        let err = runner::read_words(Box::new(unfinished.as_bytes()), "test", false)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_err();
+        assert!(err.to_string().contains("Line 4 of test"), "{}", err);
+    }
+
+    #[test]
+    fn test_dump_comments() {
+        let source = "---\ncomments: \"(* *)\"\n---\n(* Say\nhi *) Hello, (* to everyone *) aunts!";
+        let program = runner::read_words_with_comments(Box::new(source.as_bytes()), "test", false).unwrap();
+        let mut dumped = Vec::new();
+        runner::write_wottasquare(&program, &mut dumped).unwrap();
+        let dumped = String::from_utf8(dumped).unwrap();
+        assert_eq!(
+            dumped,
+            "; Say\n; hi\n\"---\\ncomments: \\\"(* *)\\\"\\n---\\n(* Say\\nhi *) \"\n[8:INPUT] \"Hello\"\n\
+             ; to everyone\n\", (* to everyone *) \"\n[5:PUSH] \"aunts\"\n\"!\"\n"
+        );
+        let scores: Vec<u8> = get_wottas(&dumped).unwrap().iter().map(|w| w.score).collect();
+        assert_eq!(scores, vec![8, 5]);
+    }
+
+    #[test]
+    fn test_annotate() {
+        let comments = vec![
+            Comment {
+                position: 0,
+                text: String::from("Say hi"),
+            },
+            Comment {
+                position: 1,
+                text: String::from("to everyone"),
+            },
+            Comment {
+                position: 2,
+                text: String::from("Done"),
+            },
+        ];
+        let text = annotate("Hello, aunts!", &comments, &Delimiters::default()).unwrap();
+        assert_eq!(
+            text,
+            "---\ncomments: \"(* *)\"\n---\n(* Say hi *) Hello, (* to everyone *) aunts! (* Done *)\n"
+        );
+        let reread = runner::read_words_with_comments(Box::new(text.as_bytes()), "test", false).unwrap();
+        assert_eq!(reread.words, get_words("Hello, aunts!").unwrap());
+        assert_eq!(reread.comments, comments);
+    }
+}
@@ -1,27 +1,49 @@
-use nom::{verbose_errors, IResult};
-use std::error;
+use crate::error::{Error, Result};
+use nom::{verbose_errors, IResult, Needed};
+use std::collections::VecDeque;
 use std::fmt;
 use std::fs::File;
-use std::io;
-use std::io::Read;
-use std::marker;
-use std::str;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
 
-pub fn io_str_error<T: error::Error + marker::Send + marker::Sync + 'static>(se: T) -> io::Error {
-    return io::Error::new(io::ErrorKind::Other, se);
+pub(crate) fn get_words_core<Parser, Filter, RawItem, Item>(
+    characters: &str,
+    function: Parser,
+    filter: Filter,
+) -> Result<Vec<Item>>
+where
+    Parser: FnMut(&str) -> IResult<&str, RawItem>,
+    Filter: FnMut(RawItem) -> Option<Item>,
+    Item: fmt::Debug,
+    RawItem: fmt::Debug,
+{
+    parse_text(characters, 1, None, true, function, filter).map(|(items, _)| items)
 }
 
-pub fn get_words_core<Parser, Filter, RawItem, Item>(
-    characters: &str,
+/// Parses as much of some text as possible, giving what's left if the parser needs more to finish an item
+/// and this isn't the end of the text. It starts at the given line of the named file, for errors.
+fn parse_text<'t, Parser, Filter, RawItem, Item>(
+    characters: &'t str,
+    first_line: usize,
+    name: Option<&str>,
+    at_end: bool,
     mut function: Parser,
     mut filter: Filter,
-) -> Result<Vec<Item>, io::Error>
+) -> Result<(Vec<Item>, &'t str)>
 where
     Parser: FnMut(&str) -> IResult<&str, RawItem>,
     Filter: FnMut(RawItem) -> Option<Item>,
     Item: fmt::Debug,
     RawItem: fmt::Debug,
 {
+    let location = |rest: &str| {
+        let offset = characters.len() - rest.len();
+        let line = first_line + characters[..offset].matches('\n').count();
+        format!(
+            "Line {}{}",
+            line,
+            name.map(|name| format!(" of {}", name)).unwrap_or_default()
+        )
+    };
     let mut remaining = characters;
     let mut result = Vec::new();
     loop {
@@ -35,41 +57,221 @@ where
                 }
                 remaining = further;
             }
-            IResult::Error(verbose_errors::Err::Position(errorkind, characters)) => {
+            IResult::Error(verbose_errors::Err::Position(errorkind, rest)) => {
                 let err = format!(
-                    "Don't know how to parse due to {:?}: {}",
+                    "{}: Don't know how to parse due to {:?}: {}",
+                    location(rest),
                     errorkind,
-                    characters.chars().take(50).collect::<String>()
+                    rest.chars().take(50).collect::<String>()
                 );
-                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                return Err(Error::Parse(err));
+            }
+            // Parsers that need the rest of the text, rather than a few more characters, say so with
+            // Needed::Unknown. Otherwise what's left at the end is only ever a partial item we skip.
+            IResult::Incomplete(Needed::Unknown) if at_end => {
+                return Err(Error::Parse(format!(
+                    "{}: Text ends part way through: {}",
+                    location(remaining),
+                    remaining.chars().take(50).collect::<String>()
+                )));
             }
             IResult::Incomplete(_) => {
                 break;
             }
             rest => {
-                panic!(format!("foo: {:?}", rest));
+                return Err(Error::Parse(format!("Unexpected parser result: {:?}", rest)));
             }
         }
     }
-    return Ok(result);
+    Ok((result, remaining))
+}
+
+/// Roughly how much text to parse at a time when streaming
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Puts the file name in front of an I/O error's message, keeping its kind
+pub(crate) fn named_io_error(filename: &str, err: io::Error) -> Error {
+    let name = if filename == "-" { "<stdio>" } else { filename };
+    Error::Io(io::Error::new(err.kind(), format!("{}: {}", name, err)))
 }
 
-pub fn get_words_core_fn<Parser, Filter, RawItem, Item>(
+/// Opens a file for reading, or stdin for "-"
+pub fn open_input(filename: &str) -> Result<Box<dyn BufRead>> {
+    if filename == "-" {
+        Ok(Box::new(io::stdin().lock()))
+    } else {
+        let f = File::open(filename).map_err(|e| named_io_error(filename, e))?;
+        Ok(Box::new(BufReader::new(f)))
+    }
+}
+
+/// Reads the whole of a file, or stdin for "-", as text
+pub fn read_input(filename: &str) -> Result<String> {
+    let mut buffer = Vec::new();
+    open_input(filename)?
+        .read_to_end(&mut buffer)
+        .map_err(|e| named_io_error(filename, e))?;
+    String::from_utf8(buffer).map_err(|e| Error::Parse(format!("{}: {}", filename, e.utf8_error())))
+}
+
+/// Creates a file for writing, or writes to stdout for "-"
+pub fn open_output(filename: &str) -> Result<Box<dyn Write>> {
+    if filename == "-" {
+        Ok(Box::new(io::stdout()))
+    } else {
+        let f = File::create(filename).map_err(|e| named_io_error(filename, e))?;
+        Ok(Box::new(BufWriter::new(f)))
+    }
+}
+
+/// Parses text from a reader a chunk at a time, so only a chunk needs to be held in memory
+pub(crate) struct Stream<'a, Parser, Filter, Item> {
+    reader: Box<dyn BufRead + 'a>,
+    name: String,
+    parser: Parser,
+    filter: Filter,
+    /// Replace invalid UTF-8 rather than failing
+    lossy: bool,
+    line: usize,
+    /// Start of the next chunk, read but not parsed yet
+    carry: String,
+    /// Which line the carried text starts on
+    carry_line: usize,
+    pending: VecDeque<Item>,
+    finished: bool,
+}
+
+impl<'a, Parser, Filter, RawItem, Item> Stream<'a, Parser, Filter, Item>
+where
+    Parser: FnMut(&str) -> IResult<&str, RawItem>,
+    Filter: FnMut(RawItem) -> Option<Item>,
+    Item: fmt::Debug,
+    RawItem: fmt::Debug,
+{
+    pub(crate) fn new(
+        reader: Box<dyn BufRead + 'a>,
+        name: &str,
+        parser: Parser,
+        filter: Filter,
+        lossy: bool,
+    ) -> Stream<'a, Parser, Filter, Item> {
+        Stream {
+            reader,
+            name: name.to_string(),
+            parser,
+            filter,
+            lossy,
+            line: 0,
+            carry: String::new(),
+            carry_line: 0,
+            pending: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    fn read_line(self: &mut Stream<'a, Parser, Filter, Item>) -> Result<Option<String>> {
+        let mut bytes = Vec::new();
+        let read = self
+            .reader
+            .read_until(b'\n', &mut bytes)
+            .map_err(|e| named_io_error(&self.name, e))?;
+        if read == 0 {
+            return Ok(None);
+        }
+        self.line += 1;
+        match String::from_utf8(bytes) {
+            Ok(line) => Ok(Some(line)),
+            Err(e) if self.lossy => {
+                warn!("Replacing invalid UTF-8 on line {} of {}", self.line, self.name);
+                Ok(Some(String::from_utf8_lossy(e.as_bytes()).into_owned()))
+            }
+            Err(e) => Err(Error::Parse(format!(
+                "Line {} of {}: {}",
+                self.line,
+                self.name,
+                e.utf8_error()
+            ))),
+        }
+    }
+
+    /// Reads and parses the next chunk. Chunks are whole lines, and only end just before a line that
+    /// starts with something other than whitespace, so no item (even a run of blank lines) is split unless
+    /// it spans lines, in which case it's tried again with the next chunk.
+    fn next_chunk(self: &mut Stream<'a, Parser, Filter, Item>) -> Result<()> {
+        let mut chunk = std::mem::take(&mut self.carry);
+        let start = if chunk.is_empty() {
+            self.line + 1
+        } else {
+            self.carry_line
+        };
+        let size = chunk.len() + CHUNK_SIZE;
+        loop {
+            match self.read_line()? {
+                Some(line) => {
+                    let starts_item = line.starts_with(|c: char| !c.is_whitespace());
+                    if chunk.len() >= size && starts_item {
+                        self.carry = line;
+                        self.carry_line = self.line;
+                        break;
+                    }
+                    chunk.push_str(&line);
+                }
+                None => {
+                    self.finished = true;
+                    break;
+                }
+            }
+        }
+        let (items, rest) = parse_text(
+            &chunk,
+            start,
+            Some(&self.name),
+            self.finished,
+            &mut self.parser,
+            &mut self.filter,
+        )?;
+        self.pending.extend(items);
+        if !rest.is_empty() {
+            let parsed = chunk.len() - rest.len();
+            self.carry_line = start + chunk[..parsed].matches('\n').count();
+            self.carry = format!("{}{}", rest, self.carry);
+        }
+        Ok(())
+    }
+}
+
+impl<'a, Parser, Filter, RawItem, Item> Iterator for Stream<'a, Parser, Filter, Item>
+where
+    Parser: FnMut(&str) -> IResult<&str, RawItem>,
+    Filter: FnMut(RawItem) -> Option<Item>,
+    Item: fmt::Debug,
+    RawItem: fmt::Debug,
+{
+    type Item = Result<Item>;
+
+    fn next(&mut self) -> Option<Result<Item>> {
+        while self.pending.is_empty() && !self.finished {
+            if let Err(e) = self.next_chunk() {
+                self.finished = true;
+                return Some(Err(e));
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+pub(crate) fn get_words_core_fn<Parser, Filter, RawItem, Item>(
     filename: &str,
     function: Parser,
     filter: Filter,
-) -> Result<Vec<Item>, io::Error>
+) -> Result<Vec<Item>>
 where
     Parser: FnMut(&str) -> IResult<&str, RawItem>,
     Filter: FnMut(RawItem) -> Option<Item>,
     Item: fmt::Debug,
     RawItem: fmt::Debug,
 {
-    let mut f = File::open(filename)?;
-    let mut buffer = Vec::new();
-    f.read_to_end(&mut buffer)?;
-    let characters = str::from_utf8(&buffer).map_err(io_str_error)?;
-    return get_words_core(characters, function, filter);
+    Stream::new(open_input(filename)?, filename, function, filter, false).collect()
 }
 
 fn is_alphabetic(c: char) -> bool {
@@ -0,0 +1,174 @@
+use crate::common;
+use crate::error::{Error, Result};
+use crate::markov::{made_up_word, title_case};
+use crate::runner;
+use rand::Rng;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Restrictions on which words from a word list get used
+#[derive(Debug, Default)]
+pub struct DictionaryFilter {
+    pub max_length: Option<usize>,
+    /// Every (lowercased) character of a word must be in here
+    pub allowed_chars: Option<BTreeSet<char>>,
+    /// Words must have at least one of these in their tag column
+    pub tags: Option<BTreeSet<String>>,
+}
+
+impl DictionaryFilter {
+    fn accepts(self: &DictionaryFilter, word: &str, tags: &[&str]) -> bool {
+        if let Some(max_length) = self.max_length {
+            if word.chars().count() > max_length {
+                return false;
+            }
+        }
+        if let Some(ref allowed) = self.allowed_chars {
+            if !word.to_lowercase().chars().all(|c| allowed.contains(&c)) {
+                return false;
+            }
+        }
+        if let Some(ref wanted) = self.tags {
+            if !tags.iter().any(|t| wanted.contains(*t)) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Words from a plain word list, indexed by score
+#[derive(Debug, Default)]
+pub struct Dictionary {
+    words: BTreeMap<u8, Vec<String>>,
+}
+
+impl Dictionary {
+    /// Parses a word list with one word per line, optionally followed by whitespace and a comma-separated
+    /// list of tags (e.g. parts of speech). Entries that aren't a single Beatnik word are skipped.
+    pub fn parse(text: &str, filter: &DictionaryFilter) -> Dictionary {
+        let mut dictionary = Dictionary::default();
+        for line in text.lines() {
+            let mut columns = line.split_whitespace();
+            let word = match columns.next() {
+                Some(word) => word,
+                None => continue,
+            };
+            let tags: Vec<&str> = columns.flat_map(|c| c.split(',')).collect();
+            let parsed = match runner::get_words(word) {
+                Ok(parsed) => parsed,
+                Err(_) => continue,
+            };
+            if parsed.len() != 1 || parsed[0].word != word {
+                debug!("Skipping '{}' as it isn't a single word", word);
+                continue;
+            }
+            if filter.accepts(word, &tags) {
+                dictionary
+                    .words
+                    .entry(parsed[0].score)
+                    .or_default()
+                    .push(word.to_string());
+            }
+        }
+        dictionary
+    }
+
+    pub fn read(filename: &str, filter: &DictionaryFilter) -> Result<Dictionary> {
+        let text = common::read_input(filename)?;
+        let dictionary = Dictionary::parse(&text, filter);
+        if dictionary.words.is_empty() {
+            return Err(Error::Parse(format!("No usable words in {}", filename)));
+        }
+        Ok(dictionary)
+    }
+
+    pub fn words_with_score(self: &Dictionary, score: u8) -> &[String] {
+        self.words.get(&score).map_or(&[], |w| w.as_slice())
+    }
+
+    fn get_word(self: &Dictionary, score: u8) -> String {
+        let mut rng = rand::thread_rng();
+        match rng.choose(self.words_with_score(score)) {
+            Some(word) => word.clone(),
+            None => made_up_word(score),
+        }
+    }
+}
+
+/// Beatnik prose for a program, picking words at random from the dictionary
+pub fn make_beatnik(words: &[runner::Word], dictionary: &Dictionary) -> String {
+    let mut out = String::new();
+    let mut line_length = 0;
+    for (index, word) in words.iter().enumerate() {
+        let mut token = dictionary.get_word(word.score());
+        if index == 0 {
+            token = title_case(&token);
+        } else if line_length + token.chars().count() >= 72 {
+            out.push('\n');
+            line_length = 0;
+        } else {
+            out.push(' ');
+            line_length += 1;
+        }
+        line_length += token.chars().count();
+        out.push_str(&token);
+    }
+    if !out.is_empty() {
+        out.push_str(".\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{make_beatnik, Dictionary, DictionaryFilter};
+    use crate::runner;
+
+    const WORDS: &str = "cat noun\ndog noun\nrun verb,noun\nzebra noun\nice cream\nbeatniks\n";
+
+    #[test]
+    fn test_filters() {
+        let all = Dictionary::parse(WORDS, &DictionaryFilter::default());
+        assert_eq!(all.words_with_score(5), &["cat", "dog", "ice"]);
+
+        let short_nouns = DictionaryFilter {
+            max_length: Some(4),
+            tags: Some(vec![String::from("noun")].into_iter().collect()),
+            ..DictionaryFilter::default()
+        };
+        let dictionary = Dictionary::parse(WORDS, &short_nouns);
+        assert_eq!(dictionary.words_with_score(5), &["cat", "dog"]);
+        assert_eq!(dictionary.words_with_score(3), &["run"]);
+        assert!(dictionary.words_with_score(16).is_empty());
+
+        let no_d = DictionaryFilter {
+            allowed_chars: Some("abcefghijklmnopqrstuvwxyz".chars().collect()),
+            ..DictionaryFilter::default()
+        };
+        let dictionary = Dictionary::parse(WORDS, &no_d);
+        assert_eq!(dictionary.words_with_score(5), &["cat", "ice"]);
+    }
+
+    #[test]
+    fn test_scores_round_trip() {
+        let dictionary = Dictionary::parse(WORDS, &DictionaryFilter::default());
+        let program = runner::get_wottas("[5:PUSH]\n[16]\n[3]\n[12]\n").unwrap();
+        let prose = make_beatnik(&program, &dictionary);
+        let scores: Vec<u8> = runner::get_words(&prose)
+            .unwrap()
+            .iter()
+            .map(|w| w.score)
+            .collect();
+        assert_eq!(scores, vec![5, 16, 3, 12]);
+    }
+
+    #[test]
+    fn test_line_width() {
+        // Five bytes but four characters, so lines should fit fourteen of them rather than twelve
+        let dictionary = Dictionary::parse("über\n", &DictionaryFilter::default());
+        let program = runner::get_wottas(&"[5:PUSH]\n".repeat(30)).unwrap();
+        let prose = make_beatnik(&program, &dictionary);
+        let widths: Vec<usize> = prose.lines().map(|line| line.chars().count()).collect();
+        assert_eq!(widths, vec![69, 69, 10]);
+    }
+}
@@ -0,0 +1,252 @@
+use crate::error::{Error, Result};
+use crate::markov::{made_up_word, MarkovInfo, Token};
+use crate::runner::{self, Word};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    /// Needs to be a no-op
+    Filler,
+    /// Needs to have the score of this program word
+    Program(usize),
+}
+
+/// What's needed to line a document up against a program, which works on part of each at a time
+struct Aligner<'a> {
+    document: &'a [Word],
+    program: &'a [Word],
+    gaps: Vec<bool>,
+}
+
+impl<'a> Aligner<'a> {
+    fn filler_cost(self: &Aligner<'a>, j: usize) -> usize {
+        usize::from(!runner::is_nop(self.document[j].score))
+    }
+
+    fn program_cost(self: &Aligner<'a>, k: usize, j: usize) -> usize {
+        usize::from(self.document[j].score != self.program[k].score)
+    }
+
+    /// The fewest changes for document words `j0..j1` to cover program words `k0..k`, for each k up to k1
+    fn forward(self: &Aligner<'a>, (j0, j1): (usize, usize), (k0, k1): (usize, usize)) -> Vec<Option<usize>> {
+        let mut costs = vec![None; k1 - k0 + 1];
+        costs[0] = Some(0);
+        for j in j0..j1 {
+            let mut next = vec![None; costs.len()];
+            for k in k0..=k1 {
+                let here = match costs[k - k0] {
+                    Some(here) => here,
+                    None => continue,
+                };
+                if self.gaps[k] {
+                    let c = here + self.filler_cost(j);
+                    next[k - k0] = Some(next[k - k0].map_or(c, |best: usize| best.min(c)));
+                }
+                if k < k1 {
+                    let c = here + self.program_cost(k, j);
+                    next[k - k0 + 1] = Some(next[k - k0 + 1].map_or(c, |best: usize| best.min(c)));
+                }
+            }
+            costs = next;
+        }
+        costs
+    }
+
+    /// The fewest changes for document words `j0..j1` to cover program words `k..k1`, for each k from k0
+    fn backward(
+        self: &Aligner<'a>,
+        (j0, j1): (usize, usize),
+        (k0, k1): (usize, usize),
+    ) -> Vec<Option<usize>> {
+        let mut costs = vec![None; k1 - k0 + 1];
+        costs[k1 - k0] = Some(0);
+        for j in (j0..j1).rev() {
+            let next: Vec<Option<usize>> = (k0..=k1)
+                .map(|k| {
+                    let filler = costs[k - k0]
+                        .filter(|_| self.gaps[k])
+                        .map(|cost| cost + self.filler_cost(j));
+                    let program = costs
+                        .get(k - k0 + 1)
+                        .cloned()
+                        .flatten()
+                        .map(|cost| cost + self.program_cost(k, j));
+                    match (filler, program) {
+                        (Some(a), Some(b)) => Some(a.min(b)),
+                        (a, b) => a.or(b),
+                    }
+                })
+                .collect();
+            costs = next;
+        }
+        costs
+    }
+
+    /// Gives document words `j0..j1` their roles covering program words `k0..k1`, by finding where the
+    /// best alignment crosses the middle document word and doing each half in turn
+    fn fill(
+        self: &Aligner<'a>,
+        roles: &mut [Role],
+        (j0, j1): (usize, usize),
+        (k0, k1): (usize, usize),
+    ) -> Option<()> {
+        match j1 - j0 {
+            0 if k1 == k0 => return Some(()),
+            1 if k1 == k0 && self.gaps[k0] => roles[j0] = Role::Filler,
+            1 if k1 == k0 + 1 => roles[j0] = Role::Program(k0),
+            0 | 1 => return None,
+            _ => {
+                let mid = (j0 + j1) / 2;
+                let before = self.forward((j0, mid), (k0, k1));
+                let after = self.backward((mid, j1), (k0, k1));
+                // Later splits win ties, so program words go as early as they can
+                let (_, k) = (k0..=k1)
+                    .filter_map(|k| Some((before[k - k0]? + after[k - k0]?, k)))
+                    .min_by_key(|&(cost, k)| (cost, std::cmp::Reverse(k)))?;
+                self.fill(roles, (j0, mid), (k0, k))?;
+                self.fill(roles, (mid, j1), (k, k1))?;
+            }
+        }
+        Some(())
+    }
+}
+
+/// Lines document words up against the program with the fewest words needing to change, giving each
+/// document word's role. Filler words can only go where runner::nop_gaps allows them. This splits the
+/// document in half at the best place and does each half separately (Hirschberg's algorithm), so only a
+/// few rows of costs are ever kept, at the price of working out each cost around twice.
+fn align(document: &[Word], program: &[Word]) -> Option<Vec<Role>> {
+    let aligner = Aligner {
+        document,
+        program,
+        gaps: runner::nop_gaps(program),
+    };
+    let mut roles = vec![Role::Filler; document.len()];
+    aligner.fill(&mut roles, (0, document.len()), (0, program.len()))?;
+    Some(roles)
+}
+
+/// Checks the words of some rewritten text each do what their role needs
+fn check_roles(words: &[Word], roles: &[Role], program: &[Word]) -> Result<()> {
+    if words.len() != roles.len() {
+        return Err(Error::Generate(format!(
+            "Rewritten text has {} words rather than {}",
+            words.len(),
+            roles.len()
+        )));
+    }
+    for (index, (word, role)) in words.iter().zip(roles).enumerate() {
+        let wanted = match role {
+            Role::Filler if !runner::is_nop(word.score) => "a no-op",
+            Role::Program(k) if word.score != program[*k].score => "the program word due there",
+            _ => continue,
+        };
+        return Err(Error::Generate(format!(
+            "Word {} '{}' (score {}) isn't {}",
+            index + 1,
+            word.word,
+            word.score,
+            wanted
+        )));
+    }
+    Ok(())
+}
+
+/// Hides a program in an existing document by swapping as few of its words as possible for words from
+/// the Markov model, so that it runs as the program. Returns the new text and how many words changed.
+pub fn embed(document: &str, program: &[Word], markov: &MarkovInfo) -> Result<(String, usize)> {
+    let words = runner::get_words(document)?;
+    let roles = align(&words, program).ok_or_else(|| {
+        Error::Generate(format!(
+            "Document has {} words, which can't hold a program of {}",
+            words.len(),
+            program.len()
+        ))
+    })?;
+    let offsets = runner::word_offsets(document)?;
+
+    let mut out = String::new();
+    let mut pos = 0;
+    let mut changes = 0;
+    let mut last = Token::Begin.string();
+    for ((word, role), offset) in words.iter().zip(roles.iter().cloned()).zip(offsets) {
+        out.push_str(&document[pos..offset]);
+        pos = offset + word.word.len();
+        let replacement = match role {
+            Role::Filler if !runner::is_nop(word.score) => Some(
+                markov
+                    .get_word_where(&last, &runner::is_nop)
+                    .unwrap_or_else(|| made_up_word(1)),
+            ),
+            Role::Program(k) if word.score != program[k].score => {
+                let score = program[k].score;
+                match markov.get_word(&last, score) {
+                    Some(new_word) => Some(new_word),
+                    None if score > 0 => Some(made_up_word(score)),
+                    None => return Err(Error::Generate(String::from("No word in the model has score 0"))),
+                }
+            }
+            _ => None,
+        };
+        let chosen = match replacement {
+            Some(new_word) => {
+                debug!("Replacing '{}' with '{}'", word.word, new_word);
+                changes += 1;
+                markov.recase(&new_word, word.word.starts_with(char::is_uppercase))
+            }
+            None => word.word.clone(),
+        };
+        out.push_str(&chosen);
+        last = chosen.to_lowercase();
+    }
+    out.push_str(&document[pos..]);
+
+    check_roles(&runner::get_words(&out)?, &roles, program)?;
+    Ok((out, changes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::embed;
+    use crate::markov;
+    use crate::runner;
+    use crate::template::check_prose;
+
+    #[test]
+    fn test_embed() {
+        let model = markov::build_markov(markov::get_tokens("a dog is in the hut").unwrap());
+        let program = runner::get_wottas("[5:PUSH]\n[3]\n").unwrap();
+        // "The" is DISCARD and "mat" a second PUSH, but "cat sat" is already PUSH 3
+        let (text, changes) = embed("The cat sat on a mat!", &program, &model).unwrap();
+        assert_eq!(changes, 2);
+        assert!(text.contains(" cat sat on a "), "{}", text);
+        assert!(text.ends_with('!'), "{}", text);
+        check_prose(&runner::get_words(&text).unwrap(), &program).unwrap();
+    }
+
+    #[test]
+    fn test_embed_nop_first() {
+        // The program starts with a no-op, so "A" has to be filler for "a" to be that word, as the skip back
+        // rules out filler after it. Checking greedily would take "A" as the program word and fail.
+        let model = markov::build_markov(markov::get_tokens("a dog").unwrap());
+        let program = runner::get_wottas("[1]\n[12:DUP]\n[15:SKIP_BACK_ZERO]\n[3]\n").unwrap();
+        assert!(check_prose(&runner::get_words("A a chew jump tea.").unwrap(), &program).is_err());
+        let (text, changes) = embed("A a chew jump tea.", &program, &model).unwrap();
+        assert_eq!((text.as_str(), changes), ("A a chew jump tea.", 0));
+    }
+
+    #[test]
+    fn test_embed_long_document() {
+        let model = markov::build_markov(markov::get_tokens("a dog is in the hut").unwrap());
+        let program = runner::get_wottas(&"[5:PUSH]\n[3]\n[9:OUTPUT]\n".repeat(100)).unwrap();
+        let document = "The cat sat on a mat, and a hat.\n".repeat(1_000);
+        let (text, _) = embed(&document, &program, &model).unwrap();
+        check_prose(&runner::get_words(&text).unwrap(), &program).unwrap();
+    }
+
+    #[test]
+    fn test_embed_too_short() {
+        let model = markov::build_markov(markov::get_tokens("a dog").unwrap());
+        let program = runner::get_wottas("[5:PUSH]\n[3]\n[9:OUTPUT]\n").unwrap();
+        assert!(embed("The cat", &program, &model).is_err());
+    }
+}
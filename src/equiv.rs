@@ -0,0 +1,239 @@
+use crate::error::Error;
+use crate::runner::{self, Command, EofBehaviour, Input, Machine, Output, Word};
+use crate::transform;
+use rand::Rng;
+use std::cmp;
+use std::fmt;
+
+/// How a run of a program stopped
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ending {
+    Finished,
+    /// Stopped by a runtime error, with its message
+    Failed(String),
+    /// Still going when it reached the step limit
+    TimedOut,
+}
+
+/// What a program did given some input
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub output: Vec<u8>,
+    pub ending: Ending,
+}
+
+/// Where two programs first differ
+#[derive(Debug, PartialEq)]
+pub enum Difference {
+    /// Their instructions differ at this word, counting from 1
+    Instruction { word: usize, a: String, b: String },
+    /// They do different things given this input
+    Behaviour { input: Vec<u8>, a: Outcome, b: Outcome },
+}
+
+fn escape(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .flat_map(|byte| std::ascii::escape_default(*byte))
+        .map(char::from)
+        .collect()
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "outputs \"{}\" then ", escape(&self.output))?;
+        match self.ending {
+            Ending::Finished => write!(f, "finishes"),
+            Ending::Failed(ref msg) => write!(f, "fails: {}", msg),
+            Ending::TimedOut => write!(f, "is still running at the step limit"),
+        }
+    }
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Difference::Instruction { word, a, b } => {
+                write!(
+                    f,
+                    "Instructions differ at word {}: A has {}, B has {}",
+                    word, a, b
+                )
+            }
+            Difference::Behaviour { input, a, b } => write!(
+                f,
+                "Behaviour differs given input \"{}\": A {}, B {}",
+                escape(input),
+                a,
+                b
+            ),
+        }
+    }
+}
+
+/// A program's instructions as they're decoded linearly, with the word each starts at. A NOP's score
+/// doesn't matter, unless the word before it takes an operand, as a skip landing there would read it.
+fn instructions(words: &[Word]) -> Vec<(usize, String)> {
+    let operands = transform::operand_slots(words);
+    let mut instructions = Vec::new();
+    for (pc, word) in words.iter().enumerate() {
+        if operands[pc] {
+            continue;
+        }
+        let command = runner::action(word.score);
+        let text = if command == Command::NOP {
+            if pc > 0 && runner::takes_operand(&runner::action(words[pc - 1].score)) {
+                format!("NOP {}", word.score)
+            } else {
+                String::from("NOP")
+            }
+        } else {
+            match words.get(pc + 1).filter(|_| runner::takes_operand(&command)) {
+                Some(operand) => format!("{:?} {}", command, operand.score),
+                None => format!("{:?}", command),
+            }
+        };
+        instructions.push((pc, text));
+    }
+    instructions.push((words.len(), String::from("the end of the program")));
+    instructions
+}
+
+/// Finds the first instruction that differs between two programs, ignoring the scores of NOPs where they
+/// can't be read
+pub fn compare_instructions(a: &[Word], b: &[Word]) -> Option<Difference> {
+    instructions(a)
+        .into_iter()
+        .zip(instructions(b))
+        .find(|(x, y)| x != y)
+        .map(|(x, y)| Difference::Instruction {
+            word: cmp::min(x.0, y.0) + 1,
+            a: x.1,
+            b: y.1,
+        })
+}
+
+/// How to run programs when comparing what they do
+pub struct RunOptions {
+    pub eof: EofBehaviour,
+    /// Most words to run before giving up on a program finishing
+    pub max_steps: usize,
+}
+
+/// Runs a program with plain Beatnik semantics on some input
+pub fn run(words: &[Word], input: &[u8], options: &RunOptions) -> Outcome {
+    let mut machine = Machine::default();
+    machine.max_steps = Some(options.max_steps);
+    let mut output = Vec::new();
+    let result = machine.run(
+        words,
+        &mut Input::new(Box::new(input), options.eof, false),
+        &mut Output::new(Box::new(&mut output), false),
+    );
+    let ending = match result {
+        Ok(()) => Ending::Finished,
+        Err(Error::Runtime(ref msg)) if msg.starts_with(runner::STEP_LIMIT) => Ending::TimedOut,
+        Err(err) => Ending::Failed(err.to_string()),
+    };
+    Outcome { output, ending }
+}
+
+/// Whether two runs could be of the same program. Output has to agree as far as both got, and runs that
+/// stopped have to stop the same way, though runtime errors can be reported at different words.
+fn agree(a: &Outcome, b: &Outcome) -> bool {
+    let shared = cmp::min(a.output.len(), b.output.len());
+    if a.output[..shared] != b.output[..shared] {
+        return false;
+    }
+    match (&a.ending, &b.ending) {
+        (Ending::TimedOut, Ending::TimedOut) => true,
+        // Whatever's still running can only add more output
+        (Ending::TimedOut, _) => a.output.len() <= b.output.len(),
+        (_, Ending::TimedOut) => b.output.len() <= a.output.len(),
+        (Ending::Failed(_), Ending::Failed(_)) => a.output == b.output,
+        (x, y) => x == y && a.output == b.output,
+    }
+}
+
+/// Runs two programs on each input, and finds the first one they behave differently on
+pub fn compare_runs(a: &[Word], b: &[Word], inputs: &[Vec<u8>], options: &RunOptions) -> Option<Difference> {
+    inputs.iter().find_map(|input| {
+        let (x, y) = (run(a, input, options), run(b, input, options));
+        if agree(&x, &y) {
+            None
+        } else {
+            Some(Difference::Behaviour {
+                input: input.clone(),
+                a: x,
+                b: y,
+            })
+        }
+    })
+}
+
+/// Random inputs of up to 16 bytes each
+pub fn random_inputs(count: usize) -> Vec<Vec<u8>> {
+    let mut rng = rand::thread_rng();
+    (0..count)
+        .map(|_| {
+            let length = rng.gen_range(0, 17);
+            (0..length).map(|_| rng.gen()).collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare_instructions, compare_runs, random_inputs, Difference, EofBehaviour, RunOptions};
+    use crate::runner::get_wottas;
+
+    const OPTIONS: RunOptions = RunOptions {
+        eof: EofBehaviour::Zero,
+        max_steps: 1000,
+    };
+
+    #[test]
+    fn test_compare_instructions() {
+        let a = get_wottas("[5:PUSH]\n[72]\n[1]\n[9:OUTPUT]\n").unwrap();
+        let b = get_wottas("[5:PUSH]\n[72]\n[20]\n[9:OUTPUT]\n").unwrap();
+        assert_eq!(compare_instructions(&a, &b), None);
+        // After a PUSH score, a skip could land on the PUSH and read the NOP
+        let a = get_wottas("[5:PUSH]\n[5]\n[1]\n[9:OUTPUT]\n").unwrap();
+        let b = get_wottas("[5:PUSH]\n[5]\n[2]\n[9:OUTPUT]\n").unwrap();
+        assert_eq!(
+            compare_instructions(&a, &b),
+            Some(Difference::Instruction {
+                word: 3,
+                a: String::from("NOP 1"),
+                b: String::from("NOP 2"),
+            })
+        );
+        let b = get_wottas("[5:PUSH]\n[5]\n").unwrap();
+        assert_eq!(
+            compare_instructions(&a, &b).unwrap().to_string(),
+            "Instructions differ at word 3: A has NOP 1, B has the end of the program"
+        );
+    }
+
+    #[test]
+    fn test_compare_runs() {
+        // Echoes a byte, with and without a pointless DUP DISCARD
+        let a = get_wottas("[8:INPUT]\n[9:OUTPUT]\n").unwrap();
+        let b = get_wottas("[8:INPUT]\n[12:DUP]\n[6:DISCARD]\n[9:OUTPUT]\n").unwrap();
+        assert!(compare_instructions(&a, &b).is_some());
+        assert_eq!(compare_runs(&a, &b, &random_inputs(20), &OPTIONS), None);
+        let c = get_wottas("[8:INPUT]\n[8:INPUT]\n[9:OUTPUT]\n").unwrap();
+        let difference = compare_runs(&a, &c, &[b"hi".to_vec()], &OPTIONS).unwrap();
+        assert_eq!(
+            difference.to_string(),
+            "Behaviour differs given input \"hi\": A outputs \"h\" then finishes, B outputs \"i\" then finishes"
+        );
+        // Loops forever, printing a's, so only the output so far is compared
+        let forever = "[5:PUSH]\n[97]\n[12:DUP]\n[12:DUP]\n[9:OUTPUT]\n[16:SKIP_BACK_NONZERO]\n[4]\n";
+        let slower = forever.replace("[16:SKIP_BACK_NONZERO]\n[4]", "[1]\n[16:SKIP_BACK_NONZERO]\n[5]");
+        let (forever, slower) = (get_wottas(forever).unwrap(), get_wottas(&slower).unwrap());
+        assert_eq!(compare_runs(&forever, &slower, &[Vec::new()], &OPTIONS), None);
+        let stops = get_wottas("[5:PUSH]\n[97]\n[9:OUTPUT]\n").unwrap();
+        assert!(compare_runs(&forever, &stops, &[Vec::new()], &OPTIONS).is_some());
+    }
+}
@@ -0,0 +1,69 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file or stream failed
+    Io(io::Error),
+    /// Source text couldn't be turned into words or tokens
+    Parse(String),
+    /// A program did something invalid while running e.g. popped an empty stack
+    Runtime(String),
+    /// A Markov model couldn't be read or written
+    Model(String),
+    /// Prose couldn't be generated that matches a program
+    Generate(String),
+    /// A program couldn't be rewritten without changing what it does
+    Transform(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Exit code when a program fails while running, or prose can't be generated for it
+pub const EXIT_RUNTIME: i32 = 1;
+/// Exit code for bad command line arguments
+pub const EXIT_USAGE: i32 = 2;
+/// Exit code when a source text, program or model can't be parsed
+pub const EXIT_PARSE: i32 = 3;
+/// Exit code when reading or writing a file or stream fails
+pub const EXIT_IO: i32 = 4;
+
+impl Error {
+    /// What the command line tool exits with for this error
+    pub fn exit_code(self: &Error) -> i32 {
+        match self {
+            Error::Io(_) => EXIT_IO,
+            Error::Parse(_) | Error::Model(_) => EXIT_PARSE,
+            Error::Runtime(_) | Error::Generate(_) | Error::Transform(_) => EXIT_RUNTIME,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Parse(msg) => write!(f, "Parse error: {}", msg),
+            Error::Runtime(msg) => write!(f, "Runtime error: {}", msg),
+            Error::Model(msg) => write!(f, "Markov model error: {}", msg),
+            Error::Generate(msg) => write!(f, "Generation error: {}", msg),
+            Error::Transform(msg) => write!(f, "Transform error: {}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
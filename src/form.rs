@@ -0,0 +1,296 @@
+use crate::error::Result;
+use crate::markov::{context, push_token, MarkovInfo, Token};
+use crate::runner::Word;
+use std::collections::BTreeMap;
+
+/// How many candidate words to try for each program word when looking for one that fits the form
+const CANDIDATES: usize = 20;
+
+/// Poetic constraints on the layout of generated Beatnik. Line breaks and punctuation are free as
+/// they're not words, so only the choice between words of the same score is affected.
+#[derive(Debug, Default, Clone)]
+pub struct Form {
+    /// Start a new line after this many words
+    pub words_per_line: Option<usize>,
+    /// Syllables wanted in each line, cycling round e.g. `[5, 7, 5]` for haiku
+    pub syllables: Option<Vec<usize>>,
+    /// Rhyme scheme e.g. "ABAB", cycling round. Lines with the same letter try to end in a rhyme.
+    pub rhyme: Option<Vec<char>>,
+}
+
+fn is_vowel(c: char) -> bool {
+    "aeiouy".contains(c)
+}
+
+/// Rough syllable count from the number of vowel groups, allowing for a silent final 'e'
+pub fn syllables(word: &str) -> usize {
+    let letters: Vec<char> = word
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphabetic())
+        .collect();
+    let mut count = 0;
+    let mut in_vowels = false;
+    for c in &letters {
+        if is_vowel(*c) && !in_vowels {
+            count += 1;
+        }
+        in_vowels = is_vowel(*c);
+    }
+    let len = letters.len();
+    if count > 1
+        && len > 2
+        && letters[len - 1] == 'e'
+        && letters[len - 2] != 'l'
+        && !is_vowel(letters[len - 2])
+    {
+        count -= 1;
+    }
+    count.max(1)
+}
+
+/// The end of a word from its last sounded vowel group onwards, which rhyming words share
+pub fn rhyme_key(word: &str) -> String {
+    let letters: Vec<char> = word
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphabetic())
+        .collect();
+    let mut end = letters.len();
+    // Skip a silent 'e' so "make" rhymes with "take" rather than everything ending in 'e'
+    if end > 2 && letters[end - 1] == 'e' && !is_vowel(letters[end - 2]) {
+        end -= 2;
+    }
+    let mut start = end;
+    while start > 0 && !is_vowel(letters[start - 1]) {
+        start -= 1;
+    }
+    while start > 0 && is_vowel(letters[start - 1]) {
+        start -= 1;
+    }
+    letters[start..].iter().collect()
+}
+
+/// The word part of a token, without any punctuation the model put in front of it
+fn word_part(token: &str) -> &str {
+    token.trim_start_matches(|c: char| !c.is_alphabetic())
+}
+
+struct Line {
+    index: usize,
+    words: usize,
+    syllables: usize,
+}
+
+impl Line {
+    fn target_syllables(self: &Line, form: &Form) -> Option<usize> {
+        form.syllables
+            .as_ref()
+            .filter(|pattern| !pattern.is_empty())
+            .map(|pattern| pattern[self.index % pattern.len()])
+    }
+
+    fn rhyme_letter(self: &Line, form: &Form) -> Option<char> {
+        form.rhyme
+            .as_ref()
+            .filter(|scheme| !scheme.is_empty())
+            .map(|scheme| scheme[self.index % scheme.len()])
+    }
+
+    /// Whether a word with this many syllables would finish the line
+    fn ends_with(self: &Line, form: &Form, syllables: usize) -> bool {
+        let by_words = form.words_per_line.is_some_and(|n| self.words + 1 >= n);
+        let by_syllables = self
+            .target_syllables(form)
+            .is_some_and(|target| self.syllables + syllables >= target);
+        by_words || by_syllables
+    }
+}
+
+/// Like markov::make_beatnik, but laid out in lines following a poetic form
+pub fn make_poem(words: &[Word], markov: &MarkovInfo, form: &Form) -> Result<String> {
+    let mut last = Token::Begin.string();
+    let mut out = String::new();
+    let mut line = Line {
+        index: 0,
+        words: 0,
+        syllables: 0,
+    };
+    let mut rhymes: BTreeMap<char, String> = BTreeMap::new();
+    for word in words {
+        let fitness = |token: &str| -> i64 {
+            let count = syllables(word_part(token));
+            let mut fit = 0;
+            if let Some(target) = line.target_syllables(form) {
+                let remaining = target.saturating_sub(line.syllables);
+                if count > remaining {
+                    fit -= 10 * (count - remaining) as i64;
+                } else if count == remaining {
+                    fit += 5;
+                }
+            }
+            if line.ends_with(form, count) {
+                let wanted = line.rhyme_letter(form).and_then(|letter| rhymes.get(&letter));
+                if wanted.is_some_and(|key| *key == rhyme_key(word_part(token))) {
+                    fit += 20;
+                }
+            }
+            fit
+        };
+        let mut token = markov.get_token(&context(&last), word.score()).replace('\n', "");
+        let mut best = fitness(&token);
+        for _ in 1..CANDIDATES {
+            let candidate = markov.get_token(&context(&last), word.score()).replace('\n', "");
+            let fit = fitness(&candidate);
+            if fit > best {
+                token = candidate;
+                best = fit;
+            }
+        }
+
+        // Any punctuation in front stays, as it may open a quote or bracket that closes later
+        token = markov.recase(&token, line.words == 0);
+        push_token(&mut out, &token);
+
+        let count = syllables(word_part(&token));
+        let ends = line.ends_with(form, count);
+        line.words += 1;
+        line.syllables += count;
+        if ends {
+            if let Some(letter) = line.rhyme_letter(form) {
+                rhymes
+                    .entry(letter)
+                    .or_insert_with(|| rhyme_key(word_part(&token)));
+            }
+            out.push('\n');
+            line = Line {
+                index: line.index + 1,
+                words: 0,
+                syllables: 0,
+            };
+            if form
+                .rhyme
+                .as_ref()
+                .is_some_and(|scheme| line.index.is_multiple_of(scheme.len()))
+            {
+                rhymes.clear();
+            }
+        }
+        last = token;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{make_poem, rhyme_key, syllables, Form};
+    use crate::markov;
+    use crate::runner;
+
+    #[test]
+    fn test_syllables() {
+        assert_eq!(syllables("cat"), 1);
+        assert_eq!(syllables("make"), 1);
+        assert_eq!(syllables("table"), 2);
+        assert_eq!(syllables("beatnik"), 2);
+        assert_eq!(syllables("Hamlet's"), 2);
+    }
+
+    #[test]
+    fn test_rhyme_key() {
+        assert_eq!(rhyme_key("cat"), rhyme_key("hat"));
+        assert_eq!(rhyme_key("light"), rhyme_key("night"));
+        assert_eq!(rhyme_key("make"), rhyme_key("take"));
+        assert_ne!(rhyme_key("make"), rhyme_key("tree"));
+    }
+
+    #[test]
+    fn test_words_per_line() {
+        let model = markov::build_markov(markov::get_tokens("the cat sat on the mat. A dog!").unwrap());
+        let program = runner::get_wottas("[5:PUSH]\n[3]\n[6]\n[1]\n[2]\n[6]\n[5]\n").unwrap();
+        let form = Form {
+            words_per_line: Some(3),
+            ..Form::default()
+        };
+        let poem = make_poem(&program, &model, &form).unwrap();
+        let lines: Vec<&str> = poem.lines().collect();
+        assert_eq!(lines.len(), 3, "{}", poem);
+        assert_eq!(runner::get_words(lines[0]).unwrap().len(), 3, "{}", poem);
+        let scores: Vec<u8> = runner::get_words(&poem)
+            .unwrap()
+            .iter()
+            .map(|w| w.score)
+            .collect();
+        assert_eq!(scores, vec![5, 3, 6, 1, 2, 6, 5]);
+    }
+
+    #[test]
+    fn test_syllables_per_line() {
+        // Each score has one word after the one before, so only the line breaks are in question
+        let model = markov::build_markov(markov::get_tokens("the table sat on a mat").unwrap());
+        let program = runner::get_wottas("[6]\n[7]\n[3]\n[2]\n[1]\n[5]\n").unwrap();
+        let form = Form {
+            syllables: Some(vec![3]),
+            ..Form::default()
+        };
+        let poem = make_poem(&program, &model, &form).unwrap();
+        let lines: Vec<usize> = poem
+            .lines()
+            .map(|line| runner::get_words(line).unwrap().len())
+            .collect();
+        assert_eq!(lines, vec![2, 3, 1], "{}", poem);
+    }
+
+    #[test]
+    fn test_rhyme_scheme() {
+        // "hat" and "bed" both score 6 after "cat", so each candidate has an even chance of rhyming
+        // and missing all of them is one in a million
+        let model = markov::build_markov(markov::get_tokens("cat hat cat bed").unwrap());
+        let program = runner::get_wottas("[5]\n[6]\n").unwrap();
+        let form = Form {
+            words_per_line: Some(1),
+            rhyme: Some(vec!['A', 'A']),
+            ..Form::default()
+        };
+        let poem = make_poem(&program, &model, &form).unwrap();
+        let lines: Vec<String> = poem.lines().map(|line| line.trim().to_lowercase()).collect();
+        assert_eq!(lines.len(), 2, "{}", poem);
+        assert!(lines[1].starts_with("hat"), "{}", poem);
+    }
+
+    #[test]
+    fn test_context_after_line_start() {
+        // "mat" scores the same as "cat" but never follows "the", even once it's been capitalised
+        let model = markov::build_markov(markov::get_tokens("the cat. mat.").unwrap());
+        let program = runner::get_wottas("[6]\n[5]\n").unwrap();
+        let form = Form {
+            words_per_line: Some(1),
+            ..Form::default()
+        };
+        for _ in 0..10 {
+            let poem = make_poem(&program, &model, &form).unwrap();
+            assert_eq!(poem, "The\nCat\n");
+        }
+    }
+
+    #[test]
+    fn test_punctuation_at_line_start() {
+        // After "cat" comes either "dog" or a bracket then "dog", so one poem in a million lacks the bracket
+        let model = markov::build_markov(markov::get_tokens("cat (dog) cat dog").unwrap());
+        let program = runner::get_wottas("[5]\n[5]\n").unwrap();
+        let form = Form {
+            words_per_line: Some(1),
+            ..Form::default()
+        };
+        let lines: Vec<String> = (0..20)
+            .map(|_| make_poem(&program, &model, &form).unwrap())
+            .map(|poem| poem.lines().nth(1).unwrap().to_string())
+            .collect();
+        assert!(
+            lines.iter().all(|line| line.trim_start_matches('(') == "Dog"),
+            "{:?}",
+            lines
+        );
+        assert!(lines.iter().any(|line| line.starts_with('(')), "{:?}", lines);
+    }
+}
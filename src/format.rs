@@ -0,0 +1,142 @@
+use crate::comments;
+
+/// How `reflow` lays out text
+pub struct FormatOptions {
+    /// Longest a line can be before it's wrapped, or None for a line per paragraph
+    pub width: Option<usize>,
+    /// Start a new line after every sentence
+    pub sentence_per_line: bool,
+}
+
+/// Whether a chunk of text ends a sentence, allowing for closing quotes and brackets after the stop
+fn ends_sentence(chunk: &str) -> bool {
+    chunk
+        .trim_end_matches(|c| "\"')]”’".contains(c))
+        .ends_with(['.', '!', '?'])
+}
+
+/// The whitespace-separated chunks of each paragraph, where paragraphs are split by blank lines
+fn paragraphs(text: &str) -> Vec<Vec<&str>> {
+    let mut paragraphs = Vec::new();
+    let mut current = Vec::new();
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                paragraphs.push(std::mem::take(&mut current));
+            }
+        } else {
+            current.extend(line.split_whitespace());
+        }
+    }
+    if !current.is_empty() {
+        paragraphs.push(current);
+    }
+    paragraphs
+}
+
+/// Lays out Beatnik prose afresh, keeping paragraphs and any front matter. Only whitespace changes, and
+/// whitespace never joins or splits a word, so every word keeps its score and place in the program.
+pub fn reflow(text: &str, options: &FormatOptions) -> String {
+    // Leading whitespace is kept as it is, as without it something that looks like front matter further
+    // down would move to the start and turn comments on
+    let body = comments::front_matter(text).map_or_else(|| text.trim_start(), |(_, body)| body);
+    let mut out = String::from(&text[..text.len() - body.len()]);
+    for (index, paragraph) in paragraphs(body).into_iter().enumerate() {
+        if index > 0 {
+            out.push('\n');
+        }
+        let mut line_length = 0;
+        for chunk in paragraph {
+            let length = chunk.chars().count();
+            if line_length > 0 {
+                if options
+                    .width
+                    .is_some_and(|width| line_length + 1 + length > width)
+                {
+                    out.push('\n');
+                    line_length = 0;
+                } else {
+                    out.push(' ');
+                    line_length += 1;
+                }
+            }
+            out.push_str(chunk);
+            line_length += length;
+            if options.sentence_per_line && ends_sentence(chunk) {
+                out.push('\n');
+                line_length = 0;
+            }
+        }
+        if line_length > 0 {
+            out.push('\n');
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reflow, FormatOptions};
+    use crate::runner::get_words;
+    use quickcheck::TestResult;
+
+    const TEXT: &str = "Hello,   aunts! Around,\naround, swim.\n\n\n  A well-known \"dog.\" Sat\n";
+
+    #[test]
+    fn test_front_matter() {
+        let options = FormatOptions {
+            width: None,
+            sentence_per_line: false,
+        };
+        let text = "---\ncomments: \"(* *)\"\n---\nHello (* a\ncomment *)\naunts!\n";
+        assert_eq!(
+            reflow(text, &options),
+            "---\ncomments: \"(* *)\"\n---\nHello (* a comment *) aunts!\n"
+        );
+    }
+
+    #[test]
+    fn test_leading_whitespace() {
+        let options = FormatOptions {
+            width: None,
+            sentence_per_line: false,
+        };
+        let text = "\n\n---\ncomments: \"(* *)\"\n---\nHello (* x *)\naunts\n";
+        let reflowed = reflow(text, &options);
+        assert!(reflowed.starts_with("\n\n---"), "{:?}", reflowed);
+        assert_eq!(get_words(&reflowed).unwrap(), get_words(text).unwrap());
+    }
+
+    #[test]
+    fn test_reflow() {
+        let wrapped = FormatOptions {
+            width: Some(14),
+            sentence_per_line: false,
+        };
+        assert_eq!(
+            reflow(TEXT, &wrapped),
+            "Hello, aunts!\nAround,\naround, swim.\n\nA well-known\n\"dog.\" Sat\n"
+        );
+        let sentences = FormatOptions {
+            width: None,
+            sentence_per_line: true,
+        };
+        assert_eq!(
+            reflow(TEXT, &sentences),
+            "Hello, aunts!\nAround, around, swim.\n\nA well-known \"dog.\"\nSat\n"
+        );
+    }
+
+    quickcheck! {
+        fn reflow_keeps_words(text: String, width: usize, sentence_per_line: bool) -> TestResult {
+            let options = FormatOptions { width: Some(width % 100), sentence_per_line };
+            let before = get_words(&text).ok();
+            let after = get_words(&reflow(&text, &options)).ok();
+            if before == after {
+                TestResult::passed()
+            } else {
+                TestResult::error(format!("{:?} became {:?}", before, after))
+            }
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Beatnik language tools: parsing and scoring Beatnik and Wottasquare programs, running them, and
+//! generating Beatnik prose from Wottasquare using Markov chains.
+
+#[macro_use]
+extern crate nom;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate enum_primitive;
+extern crate bincode;
+extern crate num_traits;
+extern crate rand;
+extern crate regex;
+extern crate serde_json;
+extern crate serde_yaml;
+extern crate unicode_normalization;
+
+#[macro_use]
+extern crate serde_derive;
+
+#[cfg(test)]
+#[macro_use]
+extern crate quickcheck;
+
+pub mod asm;
+pub mod comments;
+mod common;
+pub mod dictionary;
+pub mod embed;
+pub mod equiv;
+pub mod error;
+pub mod form;
+pub mod format;
+pub mod lint;
+pub mod markov;
+pub mod repl;
+pub mod runner;
+pub mod template;
+pub mod transform;
+
+pub use crate::common::{open_input, open_output, read_input};
+pub use crate::error::{Error, Result};
+pub use crate::runner::{get_words, get_wottas, run_beatnik, score, Word};
+
+#[cfg(test)]
+mod tests {
+    use crate::markov;
+    use crate::runner;
+    use quickcheck::TestResult;
+
+    fn wotta_two_way(xs: Vec<u8>, source_words: String) -> Result<(), String> {
+        let mut info = String::new();
+        info.push_str(&format!("Source_words: {:?}\n", source_words));
+        let mut markov = markov::MarkovInfo::new();
+        let tokens = markov::get_tokens(&source_words).unwrap();
+        let mut last = markov::Token::Begin.string();
+        for token in tokens {
+            let str_token = token.string();
+            markov.add_token(last, &str_token);
+            last = str_token;
+        }
+        info.push_str(&format!("Markov: {:?}\n", markov));
+
+        let words: Vec<runner::Word> = xs
+            .iter()
+            .filter(|x| *x != &0)
+            .map(|x| runner::Word {
+                score: *x,
+                word: String::from(""),
+            })
+            .collect();
+        info.push_str(&format!("Score in: {:?}\n", xs));
+        let markov_out = markov::make_beatnik(&words, &markov).unwrap();
+        info.push_str(&format!("Markov out: {:?}\n", markov_out));
+        let words_out = runner::get_words(&markov_out).unwrap();
+        info.push_str(&format!("Words out: {:?}\n", words_out));
+        let score_out: Vec<u8> = words_out.iter().map(|x| x.score).filter(|x| x != &0).collect();
+        info.push_str(&format!("Score out: {:?}\n", score_out));
+        let res = xs.into_iter().filter(|x| x != &0).collect::<Vec<u8>>() == score_out;
+        info.push_str(&format!("Res: {}\n", res));
+        if res {
+            Ok(())
+        } else {
+            Err(info)
+        }
+    }
+
+    #[test]
+    fn test_wotta_two_way_utf() {
+        wotta_two_way(vec![3], String::from("ŉb")).unwrap();
+    }
+
+    quickcheck! {
+        fn wotta_two_way_qc(xs: Vec<u8>, source_words: String) -> TestResult {
+            let res = wotta_two_way(xs, source_words);
+            match res {
+                Ok(_) => TestResult::passed(),
+                Err(info) => TestResult::error(info)
+            }
+        }
+    }
+}
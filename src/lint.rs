@@ -0,0 +1,180 @@
+use crate::error::Result;
+use crate::runner::{self, Word};
+use crate::transform;
+use std::fmt;
+
+const APOSTROPHES: &[char] = &['\'', '’'];
+const DASHES: &[char] = &['-', '\u{2014}'];
+
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    /// Runs as a command even though it's in an aside (in brackets, or on a line starting with '#')
+    CommandInAside,
+    /// Only one word because of the hyphens or dashes joining it, which other interpreters may split on
+    JoinedWord,
+    /// Next to an apostrophe that might be a quote mark, which is read as part of the word if it's after it
+    /// and left out if it's before it
+    AmbiguousApostrophe,
+}
+
+/// Something in a Beatnik program that probably doesn't do what its author meant
+#[derive(Debug)]
+pub struct Lint {
+    /// Line and character on that line, both counting from 1
+    pub line: usize,
+    pub column: usize,
+    pub word: Word,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: ", self.line, self.column)?;
+        let word = &self.word.word;
+        match self.problem {
+            Problem::CommandInAside => write!(
+                f,
+                "\"{}\" is in an aside but runs as {:?}",
+                word,
+                runner::action(self.word.score)
+            ),
+            Problem::JoinedWord => {
+                let parts: Vec<String> = word
+                    .split(DASHES)
+                    .filter(|part| !part.is_empty())
+                    .map(|part| format!("\"{}\" ({})", part, runner::score(&part.to_lowercase())))
+                    .collect();
+                write!(
+                    f,
+                    "\"{}\" ({}) is only one word because of its dashes, and might be read as {}",
+                    word,
+                    self.word.score,
+                    parts.join(" ")
+                )
+            }
+            Problem::AmbiguousApostrophe => {
+                write!(f, "\"{}\" has an apostrophe that might be a quote mark", word)
+            }
+        }
+    }
+}
+
+/// Whether each word is in an aside, by its byte offset
+fn in_aside(text: &str, offsets: &[usize]) -> Vec<bool> {
+    let mut asides = Vec::with_capacity(offsets.len());
+    let mut depth = 0usize;
+    let mut comment_line = false;
+    let mut line_start = true;
+    let mut chars = text.char_indices().peekable();
+    for offset in offsets {
+        while let Some((_, c)) = chars.next_if(|(i, _)| i < offset) {
+            match c {
+                '\n' => {
+                    comment_line = false;
+                    line_start = true;
+                    continue;
+                }
+                '(' | '[' => depth += 1,
+                ')' | ']' => depth = depth.saturating_sub(1),
+                '#' if line_start => comment_line = true,
+                _ => {}
+            }
+            line_start &= c.is_whitespace();
+        }
+        asides.push(depth > 0 || comment_line);
+    }
+    asides
+}
+
+/// The line and column of each word, by its byte offset, both counting from 1
+fn positions(text: &str, offsets: &[usize]) -> Vec<(usize, usize)> {
+    let mut positions = Vec::with_capacity(offsets.len());
+    let (mut line, mut column) = (1, 1);
+    let mut chars = text.char_indices().peekable();
+    for offset in offsets {
+        while let Some((_, c)) = chars.next_if(|(i, _)| i < offset) {
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        positions.push((line, column));
+    }
+    positions
+}
+
+fn ambiguous_apostrophe(text: &str, offset: usize, word: &str) -> bool {
+    let before = &text[..offset];
+    let quoted = before.ends_with(APOSTROPHES)
+        && !before
+            .trim_end_matches(APOSTROPHES)
+            .ends_with(|c: char| c.is_alphanumeric());
+    quoted || word.ends_with(APOSTROPHES)
+}
+
+/// Finds the words in some Beatnik prose that probably aren't what was meant
+pub fn lint(text: &str) -> Result<Vec<Lint>> {
+    let words = runner::get_words(text)?;
+    let offsets = runner::word_offsets(text)?;
+    let asides = in_aside(text, &offsets);
+    let positions = positions(text, &offsets);
+    let operands = transform::operand_slots(&words);
+    let mut lints = Vec::new();
+    for (index, word) in words.into_iter().enumerate() {
+        let offset = offsets[index];
+        let mut problems = Vec::new();
+        if asides[index] && !operands[index] && !runner::is_nop(word.score) {
+            problems.push(Problem::CommandInAside);
+        }
+        if word.word.split(DASHES).filter(|part| !part.is_empty()).count() > 1 {
+            problems.push(Problem::JoinedWord);
+        }
+        if ambiguous_apostrophe(text, offset, &word.word) {
+            problems.push(Problem::AmbiguousApostrophe);
+        }
+        let (line, column) = positions[index];
+        for problem in problems {
+            lints.push(Lint {
+                line,
+                column,
+                word: word.clone(),
+                problem,
+            });
+        }
+    }
+    Ok(lints)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lint, Problem};
+
+    #[test]
+    fn test_lint() {
+        let text = "A well-known dog (shall bark)\n# Trace the cat\nThe dogs' 'tis ok, isn't it";
+        let found: Vec<(usize, usize, String, Problem)> = lint(text)
+            .unwrap()
+            .into_iter()
+            .map(|lint| (lint.line, lint.column, lint.word.word, lint.problem))
+            .collect();
+        let expected = vec![
+            (1, 3, "well-known", Problem::JoinedWord),
+            // "shall" would be INPUT, but it's the operand for "dog"
+            (1, 25, "bark", Problem::CommandInAside),
+            (2, 3, "Trace", Problem::CommandInAside),
+            (2, 9, "the", Problem::CommandInAside),
+            (2, 13, "cat", Problem::CommandInAside),
+            (3, 5, "dogs'", Problem::AmbiguousApostrophe),
+            (3, 12, "tis", Problem::AmbiguousApostrophe),
+        ];
+        assert_eq!(
+            found,
+            expected
+                .into_iter()
+                .map(|(line, column, word, problem)| (line, column, String::from(word), problem))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -1,61 +1,342 @@
-#[macro_use]
-extern crate nom;
-#[macro_use]
-extern crate log;
 extern crate env_logger;
 #[macro_use]
-extern crate enum_primitive;
 extern crate clap;
-extern crate num_traits;
-extern crate rand;
-extern crate serde_yaml;
-extern crate unicode_normalization;
+extern crate peacenik;
+extern crate regex;
 
-#[macro_use]
-extern crate serde_derive;
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+use peacenik::{
+    asm, comments, dictionary, embed, equiv, error, form, format, lint, markov, repl, runner, template,
+    transform,
+};
+use regex::Regex;
+use std::io::{self, Read, Write};
+use std::process;
 
-#[cfg(test)]
-#[macro_use]
-extern crate quickcheck;
-
-mod common;
-mod markov;
-mod runner;
-
-use clap::{App, Arg, SubCommand};
-use std::fs::File;
-use std::io::{self, Write};
-use std::str;
-
-fn word_parser(items: Result<Vec<runner::Word>, io::Error>) -> Vec<runner::Word> {
-    match items {
-        Ok(val) => val,
-        Err(err) => {
-            if err.kind() == io::ErrorKind::InvalidData {
-                println!("{}", err.get_ref().unwrap());
-                std::process::exit(-1);
-            } else {
-                panic!("Error during parsing: {}", err);
+/// Why a command stopped early
+enum Failure {
+    /// Bad arguments, or a request for help or the version
+    Usage(clap::Error),
+    Program(peacenik::Error),
+    /// How many problems the linter found
+    Lints(usize),
+    /// Two programs aren't equivalent, which has already been reported
+    Differs,
+}
+
+impl From<clap::Error> for Failure {
+    fn from(err: clap::Error) -> Failure {
+        Failure::Usage(err)
+    }
+}
+
+impl From<peacenik::Error> for Failure {
+    fn from(err: peacenik::Error) -> Failure {
+        Failure::Program(err)
+    }
+}
+
+impl Failure {
+    /// Tells the user what went wrong, and gives the exit code for it
+    fn report(self: Failure) -> i32 {
+        match self {
+            // Help and version aren't errors, and clap prints them and exits successfully itself
+            Failure::Usage(err) if !err.use_stderr() => err.exit(),
+            Failure::Usage(err) => {
+                eprintln!("{}", err.message);
+                error::EXIT_USAGE
+            }
+            // Whatever was reading our output has gone away, so there's no-one left to tell
+            Failure::Program(peacenik::Error::Io(ref err)) if err.kind() == io::ErrorKind::BrokenPipe => 0,
+            Failure::Program(err) => {
+                eprintln!("error: {}", err);
+                err.exit_code()
             }
+            Failure::Lints(count) => {
+                eprintln!("Found {} problem{}", count, if count == 1 { "" } else { "s" });
+                error::EXIT_RUNTIME
+            }
+            Failure::Differs => error::EXIT_RUNTIME,
         }
     }
 }
 
+/// Puts the file name in front of parse errors from text that was read in whole
+fn in_file<T>(fname: &str, result: peacenik::Result<T>) -> peacenik::Result<T> {
+    result.map_err(|err| match err {
+        peacenik::Error::Parse(msg) => peacenik::Error::Parse(format!("{}: {}", fname, msg)),
+        other => other,
+    })
+}
+
+fn lossy_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("LOSSY")
+        .long("lossy")
+        .help("Replace invalid UTF-8 in the input with a warning, rather than failing")
+}
+
+/// Reads the program from the INPUT argument, which can be "-" for stdin
+fn source_program(args: &ArgMatches, wottasquare: bool) -> peacenik::Result<runner::Program> {
+    let input_fname = required(args, "INPUT");
+    let lossy = args.is_present("LOSSY");
+    let reader = peacenik::open_input(input_fname)?;
+    if wottasquare {
+        runner::read_wottas_with_comments(reader, input_fname, lossy)
+    } else {
+        runner::read_words_with_comments(reader, input_fname, lossy)
+    }
+}
+
+/// Reads the Wottasquare program for a generator, keeping its comments to pass on
+fn wotta_program(input_fname: &str) -> peacenik::Result<runner::Program> {
+    runner::read_wottas_with_comments(peacenik::open_input(input_fname)?, input_fname, false)
+}
+
+/// Writes generated Beatnik, with the program's comments put back in
+fn write_generated(output_fname: &str, text: &str, program: &runner::Program) -> peacenik::Result<()> {
+    let text = comments::annotate(text, &program.comments, &comments::Delimiters::default())?;
+    write_output(output_fname, &text)
+}
+
+/// The value of an argument clap has already made sure was given
+fn required<'a>(args: &'a ArgMatches, name: &str) -> &'a str {
+    args.value_of(name).unwrap_or_default()
+}
+
+fn write_output(output_fname: &str, text: &str) -> peacenik::Result<()> {
+    let mut buffer = peacenik::open_output(output_fname)?;
+    buffer
+        .write_all(text.as_bytes())
+        .and_then(|_| buffer.flush())
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", output_fname, e)).into())
+}
+
+/// Stops with a usage error if more than one of these arguments wants to read stdin
+fn single_stdin(args: &ArgMatches, names: &[&str]) -> Result<(), Failure> {
+    // An argument given more than once counts once for each "-"
+    let readers: Vec<&str> = names
+        .iter()
+        .flat_map(|name| {
+            args.values_of(name)
+                .into_iter()
+                .flatten()
+                .filter(|value| *value == "-")
+                .map(move |_| *name)
+        })
+        .collect();
+    if readers.len() > 1 {
+        let mut named = readers.clone();
+        named.dedup();
+        return Err(Failure::Usage(clap::Error::with_description(
+            &format!("Only one of {} can read from stdin", named.join(", ")),
+            clap::ErrorKind::ArgumentConflict,
+        )));
+    }
+    Ok(())
+}
+
+fn run_args<'a, 'b>(cmd: App<'a, 'b>) -> App<'a, 'b> {
+    cmd.arg(lossy_arg())
+        .arg(
+            Arg::with_name("INPUT_FILE")
+                .long("input-file")
+                .takes_value(true)
+                .help("Read the program's INPUT from this file rather than stdin"),
+        )
+        .arg(
+            Arg::with_name("EOF")
+                .long("eof")
+                .takes_value(true)
+                .possible_values(&["zero", "max", "halt"])
+                .default_value("zero")
+                .help("What INPUT does at end of input: push 0, push 255 or halt"),
+        )
+        .arg(
+            Arg::with_name("LINE_BUFFERED")
+                .long("line-buffered")
+                .help("Read input a line at a time rather than all at once"),
+        )
+        .arg(
+            Arg::with_name("INTERACTIVE")
+                .long("interactive")
+                .help("Line-buffered input and unbuffered output, for conversational programs"),
+        )
+}
+
+fn stack_args<'a, 'b>(cmd: App<'a, 'b>) -> App<'a, 'b> {
+    cmd.arg(
+        Arg::with_name("MAX_STACK")
+            .long("max-stack")
+            .takes_value(true)
+            .help("Error if the stack grows deeper than this"),
+    )
+    .arg(
+        Arg::with_name("CELL")
+            .long("cell")
+            .takes_value(true)
+            .possible_values(&["u8", "u16", "u32", "i64"])
+            .default_value("u8")
+            .help("Width of each stack cell"),
+    )
+    .arg(
+        Arg::with_name("CHECKED")
+            .long("checked")
+            .help("Error on arithmetic overflow rather than wrapping"),
+    )
+}
+
+fn stack_options(args: &ArgMatches) -> Result<runner::StackOptions, Failure> {
+    let max_depth = if args.is_present("MAX_STACK") {
+        Some(value_t!(args, "MAX_STACK", usize)?)
+    } else {
+        None
+    };
+    Ok(runner::StackOptions {
+        max_depth,
+        cell: value_t!(args, "CELL", runner::CellWidth)?,
+        arithmetic: if args.is_present("CHECKED") {
+            runner::Arithmetic::Checked
+        } else {
+            runner::Arithmetic::Wrapping
+        },
+    })
+}
+
+fn format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("FORMAT")
+        .long("format")
+        .takes_value(true)
+        .possible_values(&["yaml", "json", "binary"])
+        .help("Model format to write, otherwise guessed from the output's extension (.json or .bin)")
+}
+
+fn prune_args<'a, 'b>(cmd: App<'a, 'b>) -> App<'a, 'b> {
+    cmd.arg(
+        Arg::with_name("MIN_COUNT")
+            .long("min-count")
+            .takes_value(true)
+            .help("Drop words seen fewer times than this"),
+    )
+    .arg(
+        Arg::with_name("WORDS")
+            .long("words")
+            .takes_value(true)
+            .help("Only keep words in this file, one per line"),
+    )
+    .arg(
+        Arg::with_name("REGEX")
+            .long("regex")
+            .takes_value(true)
+            .help("Only keep words matching this regular expression"),
+    )
+    .arg(
+        Arg::with_name("EXCLUDE")
+            .long("exclude")
+            .takes_value(true)
+            .help("Never keep words in this file, one per line e.g. a profanity list"),
+    )
+    .arg(
+        Arg::with_name("NORMALISE")
+            .long("normalise")
+            .help("Lowercase and Unicode-normalise words, merging duplicates"),
+    )
+}
+
+fn prune_options(args: &ArgMatches) -> Result<markov::PruneOptions, Failure> {
+    let pattern = match args.value_of("REGEX") {
+        Some(pattern) => {
+            Some(Regex::new(pattern).map_err(|e| clap::Error::value_validation_auto(e.to_string()))?)
+        }
+        None => None,
+    };
+    Ok(markov::PruneOptions {
+        min_count: if args.is_present("MIN_COUNT") {
+            value_t!(args, "MIN_COUNT", u32)?
+        } else {
+            0
+        },
+        allowed: args.value_of("WORDS").map(markov::read_word_list).transpose()?,
+        pattern,
+        excluded: args
+            .value_of("EXCLUDE")
+            .map(markov::read_word_list)
+            .transpose()?
+            .unwrap_or_default(),
+        normalise: args.is_present("NORMALISE"),
+    })
+}
+
+fn model_format(args: &ArgMatches, output_fname: &str) -> Result<markov::ModelFormat, Failure> {
+    if args.is_present("FORMAT") {
+        Ok(value_t!(args, "FORMAT", markov::ModelFormat)?)
+    } else {
+        Ok(markov::ModelFormat::from_filename(output_fname))
+    }
+}
+
+/// Reads a program's words, as Wottasquare if the file name ends in .wottasquare and Beatnik otherwise
+fn any_program(fname: &str) -> peacenik::Result<Vec<runner::Word>> {
+    let reader = peacenik::open_input(fname)?;
+    let program = if fname.ends_with(".wottasquare") {
+        runner::read_wottas_with_comments(reader, fname, false)?
+    } else {
+        runner::read_words_with_comments(reader, fname, false)?
+    };
+    Ok(program.words)
+}
+
+/// The inputs to run programs on when comparing them
+fn equiv_inputs(args: &ArgMatches) -> Result<Vec<Vec<u8>>, Failure> {
+    let mut inputs = Vec::new();
+    for fname in args.values_of("INPUT_FILE").into_iter().flatten() {
+        let mut input = Vec::new();
+        peacenik::open_input(fname)?
+            .read_to_end(&mut input)
+            .map_err(|e| peacenik::Error::from(io::Error::new(e.kind(), format!("{}: {}", fname, e))))?;
+        inputs.push(input);
+    }
+    if args.is_present("RANDOM") {
+        inputs.extend(equiv::random_inputs(value_t!(args, "RANDOM", usize)?));
+    }
+    Ok(inputs)
+}
+
+fn run_program(words: &[runner::Word], args: &ArgMatches) -> Result<(), Failure> {
+    let eof = value_t!(args, "EOF", runner::EofBehaviour)?;
+    let interactive = args.is_present("INTERACTIVE");
+    let line_buffered = interactive || args.is_present("LINE_BUFFERED");
+    let mut input = match args.value_of("INPUT_FILE") {
+        Some(fname) => runner::Input::new(peacenik::open_input(fname)?, eof, line_buffered),
+        None => runner::Input::stdin(eof, line_buffered),
+    };
+    let mut output = runner::Output::stdout(interactive);
+    runner::Machine::with_options(stack_options(args)?).run(words, &mut input, &mut output)?;
+    Ok(())
+}
+
 fn main() {
-    env_logger::init().unwrap();
+    // Only fails if a logger is already set up, which is fine
+    env_logger::init().ok();
+    if let Err(failure) = run() {
+        process::exit(failure.report());
+    }
+}
+
+fn run() -> Result<(), Failure> {
     let app = App::new("peacenik")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
         .version("1.0")
         .author("Tom Parker <palfrey@tevp.net>")
         .about("Beatnik language tools")
-        .subcommand(
+        .subcommand(stack_args(run_args(
             SubCommand::with_name("run").about("Beatnik interpreter").arg(
                 Arg::with_name("INPUT")
                     .help("Sets the input file to use")
                     .required(true)
                     .index(1),
             ),
-        )
-        .subcommand(
+        )))
+        .subcommand(stack_args(run_args(
             SubCommand::with_name("wottasquare")
                 .about("Wottasquare interpreter")
                 .arg(
@@ -64,7 +345,10 @@ fn main() {
                         .required(true)
                         .index(1),
                 ),
-        )
+        )))
+        .subcommand(stack_args(
+            SubCommand::with_name("repl").about("Interactive Beatnik interpreter"),
+        ))
         .subcommand(
             SubCommand::with_name("wottasquare-dumper")
                 .about("Wottasquare dumper")
@@ -73,9 +357,27 @@ fn main() {
                         .help("Sets the input file to use")
                         .required(true)
                         .index(1),
-                ),
+                )
+                .arg(lossy_arg()),
         )
         .subcommand(
+            SubCommand::with_name("restore-beatnik")
+                .about("Turns a Wottasquare dump back into the exact Beatnik it came from")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input file to use")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .short("o")
+                        .takes_value(true)
+                        .default_value("-")
+                        .help("Sets the output file to use"),
+                ),
+        )
+        .subcommand(prune_args(
             SubCommand::with_name("generate-markov")
                 .about("Markov chain generator")
                 .arg(
@@ -91,6 +393,56 @@ fn main() {
                         .takes_value(true)
                         .help("Sets the output file to use")
                         .required(true),
+                )
+                .arg(lossy_arg())
+                .arg(format_arg()),
+        ))
+        .subcommand(
+            SubCommand::with_name("convert-markov")
+                .about("Converts a Markov chain between YAML, JSON and binary")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .short("i")
+                        .takes_value(true)
+                        .help("Sets the input file to use, in any format")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .short("o")
+                        .takes_value(true)
+                        .help("Sets the output file to use")
+                        .required(true),
+                )
+                .arg(format_arg()),
+        )
+        .subcommand(prune_args(
+            SubCommand::with_name("prune-markov")
+                .about("Drops unwanted words from a Markov chain")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .short("i")
+                        .takes_value(true)
+                        .help("Sets the input file to use")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .short("o")
+                        .takes_value(true)
+                        .help("Sets the output file to use")
+                        .required(true),
+                )
+                .arg(format_arg()),
+        ))
+        .subcommand(
+            SubCommand::with_name("markov-stats")
+                .about("Reports on what's in a Markov chain")
+                .arg(
+                    Arg::with_name("MODEL")
+                        .help("Sets the markov file to use")
+                        .required(true)
+                        .index(1),
                 ),
         )
         .subcommand(
@@ -116,108 +468,515 @@ fn main() {
                         .takes_value(true)
                         .help("Sets the output file to use")
                         .required(true),
+                )
+                .arg(
+                    Arg::with_name("LINE_WORDS")
+                        .long("line-words")
+                        .takes_value(true)
+                        .help("Start a new line after this many words"),
+                )
+                .arg(
+                    Arg::with_name("SYLLABLES")
+                        .long("syllables")
+                        .takes_value(true)
+                        .use_delimiter(true)
+                        .help("Syllables per line, or a comma-separated cycle of them e.g. 5,7,5"),
+                )
+                .arg(
+                    Arg::with_name("RHYME")
+                        .long("rhyme")
+                        .takes_value(true)
+                        .requires("LINE_ENDS")
+                        .help("Rhyme scheme to try for at line ends e.g. ABAB, with --line-words or --syllables"),
+                )
+                // Lines only end, and so can only rhyme, when one of these is given
+                .group(
+                    ArgGroup::with_name("LINE_ENDS")
+                        .args(&["LINE_WORDS", "SYLLABLES"])
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("PAD")
+                        .long("pad")
+                        .takes_value(true)
+                        .conflicts_with_all(&["LINE_WORDS", "SYLLABLES", "RHYME"])
+                        .help("Allow up to this many no-op words before each word if it reads better"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("template-beatnik")
+                .about("Beatnik from Wottasquare by filling the {slots} in a template using Markov")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .short("i")
+                        .takes_value(true)
+                        .help("Sets the input file to use")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("MARKOV")
+                        .short("m")
+                        .takes_value(true)
+                        .help("Sets the markov file to use")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("TEMPLATE")
+                        .short("t")
+                        .takes_value(true)
+                        .help("Sets the template file to use")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .short("o")
+                        .takes_value(true)
+                        .help("Sets the output file to use")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("embed")
+                .about(
+                    "Hide a Wottasquare program in an existing document by changing as few words as possible",
+                )
+                .arg(
+                    Arg::with_name("INPUT")
+                        .short("i")
+                        .takes_value(true)
+                        .help("Sets the input file to use")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("DOCUMENT")
+                        .short("d")
+                        .takes_value(true)
+                        .help("Sets the document to hide the program in")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("MARKOV")
+                        .short("m")
+                        .takes_value(true)
+                        .help("Sets the markov file to use for replacement words")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .short("o")
+                        .takes_value(true)
+                        .help("Sets the output file to use")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dict-beatnik")
+                .about("Beatnik from Wottasquare using a word list")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .short("i")
+                        .takes_value(true)
+                        .help("Sets the input file to use")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("DICTIONARY")
+                        .short("d")
+                        .takes_value(true)
+                        .help("Sets the word list to use, one word per line with optional tags after it")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .short("o")
+                        .takes_value(true)
+                        .help("Sets the output file to use")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("MAX_LENGTH")
+                        .long("max-length")
+                        .takes_value(true)
+                        .help("Only use words up to this many characters long"),
+                )
+                .arg(
+                    Arg::with_name("CHARS")
+                        .long("chars")
+                        .takes_value(true)
+                        .help("Only use words made entirely of these characters"),
+                )
+                .arg(
+                    Arg::with_name("TAGS")
+                        .long("tags")
+                        .takes_value(true)
+                        .use_delimiter(true)
+                        .help("Only use words tagged with one of these (comma-separated)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fmt")
+                .about("Reflows Beatnik prose without changing what it does")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input file to use")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .short("o")
+                        .takes_value(true)
+                        .default_value("-")
+                        .help("Sets the output file to use"),
+                )
+                .arg(
+                    Arg::with_name("WIDTH")
+                        .long("width")
+                        .takes_value(true)
+                        .default_value("80")
+                        .help("Wrap lines longer than this, or 0 for a line per paragraph"),
+                )
+                .arg(
+                    Arg::with_name("SENTENCE_PER_LINE")
+                        .long("sentence-per-line")
+                        .help("Start a new line after every sentence"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("disassemble")
+                .about("Lists a Beatnik program as assembly, with operands inline and labels for skips")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input file to use")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .short("o")
+                        .takes_value(true)
+                        .default_value("-")
+                        .help("Sets the output file to use"),
+                )
+                .arg(
+                    Arg::with_name("WOTTASQUARE")
+                        .long("wottasquare")
+                        .help("Read Wottasquare rather than Beatnik"),
+                )
+                .arg(lossy_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("assemble")
+                .about("Turns assembly from disassemble back into Wottasquare with the same scores, though not the same words")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input file to use")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .short("o")
+                        .takes_value(true)
+                        .default_value("-")
+                        .help("Sets the output file to use"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("optimise")
+                .about(
+                    "Shortens a program, writing it as Wottasquare, so the prose generated for it is shorter",
+                )
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input file to use")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .short("o")
+                        .takes_value(true)
+                        .default_value("-")
+                        .help("Sets the output file to use"),
+                )
+                .arg(
+                    Arg::with_name("WOTTASQUARE")
+                        .long("wottasquare")
+                        .help("Read Wottasquare rather than Beatnik"),
+                )
+                .arg(lossy_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("equiv")
+                .about(
+                    "Checks two programs (Beatnik, or Wottasquare if named .wottasquare) do the same thing",
+                )
+                .arg(
+                    Arg::with_name("A")
+                        .help("Sets the first program to compare")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("B")
+                        .help("Sets the second program to compare")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("INPUT_FILE")
+                        .long("input-file")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Run both programs with this as their INPUT and compare what they do"),
+                )
+                .arg(
+                    Arg::with_name("RANDOM")
+                        .long("random")
+                        .takes_value(true)
+                        .help("Run both programs on this many random inputs and compare what they do"),
+                )
+                .arg(
+                    Arg::with_name("MAX_STEPS")
+                        .long("max-steps")
+                        .takes_value(true)
+                        .default_value("100000")
+                        .help("Words to run before only comparing the output so far"),
+                )
+                .arg(
+                    Arg::with_name("EOF")
+                        .long("eof")
+                        .takes_value(true)
+                        .possible_values(&["zero", "max", "halt"])
+                        .default_value("zero")
+                        .help("What INPUT does at end of input: push 0, push 255 or halt"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("lint")
+                .about("Points out Beatnik prose that probably doesn't do what was meant")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input file to use")
+                        .required(true)
+                        .index(1),
                 ),
         );
-    let mut usage = Vec::new();
-    app.write_help(&mut usage).unwrap();
-    match app.get_matches().subcommand() {
+    match app.get_matches_safe()?.subcommand() {
         ("run", Some(args)) => {
-            let input_fname = args.value_of("INPUT").expect("input filename");
-            let items = runner::get_words_fn(input_fname);
-            let words = word_parser(items);
-            runner::run_beatnik(&words);
+            single_stdin(args, &["INPUT", "INPUT_FILE"])?;
+            let program = source_program(args, false)?;
+            run_program(&program.words, args)?;
         }
         ("wottasquare", Some(args)) => {
-            let input_fname = args.value_of("INPUT").expect("input filename");
-            let items = runner::get_wottas_fn(input_fname);
-            let words = word_parser(items);
-            runner::run_beatnik(&words);
+            single_stdin(args, &["INPUT", "INPUT_FILE"])?;
+            let program = source_program(args, true)?;
+            run_program(&program.words, args)?;
+        }
+        ("repl", Some(args)) => {
+            repl::run_repl(stack_options(args)?)?;
         }
         ("wottasquare-dumper", Some(args)) => {
-            let input_fname = args.value_of("INPUT").expect("input filename");
-            let items = runner::get_words_fn(input_fname);
-            let words = word_parser(items);
-            runner::output_wottasquare(words);
+            let program = source_program(args, false)?;
+            runner::write_wottasquare(&program, &mut io::stdout().lock())?;
+        }
+        ("restore-beatnik", Some(args)) => {
+            let source = source_program(args, true)?.source()?;
+            write_output(required(args, "OUTPUT"), &source)?;
         }
         ("generate-markov", Some(args)) => {
-            let input_fname = args.value_of("INPUT").unwrap();
-            let markov = markov::generate_markov(input_fname).expect("markov");
-            let output_fname = args.value_of("OUTPUT").expect("output name");
-            let mut buffer = File::create(output_fname).unwrap();
-            serde_yaml::to_writer(&mut buffer, &markov).unwrap();
+            single_stdin(args, &["INPUT", "WORDS", "EXCLUDE"])?;
+            let input_fname = required(args, "INPUT");
+            let markov =
+                markov::generate_markov(input_fname, args.is_present("LOSSY"))?.prune(&prune_options(args)?);
+            let output_fname = required(args, "OUTPUT");
+            markov::write_markov(output_fname, &markov, model_format(args, output_fname)?)?;
+        }
+        ("convert-markov", Some(args)) => {
+            let input_fname = required(args, "INPUT");
+            let markov = markov::read_markov(input_fname)?;
+            let output_fname = required(args, "OUTPUT");
+            markov::write_markov(output_fname, &markov, model_format(args, output_fname)?)?;
+        }
+        ("prune-markov", Some(args)) => {
+            single_stdin(args, &["INPUT", "WORDS", "EXCLUDE"])?;
+            let input_fname = required(args, "INPUT");
+            let markov = markov::read_markov(input_fname)?.prune(&prune_options(args)?);
+            let output_fname = required(args, "OUTPUT");
+            markov::write_markov(output_fname, &markov, model_format(args, output_fname)?)?;
+        }
+        ("markov-stats", Some(args)) => {
+            let markov_fname = required(args, "MODEL");
+            let markov_data = markov::read_markov(markov_fname)?;
+            write_output("-", &markov_data.stats().to_string())?;
         }
         ("markov-beatnik", Some(args)) => {
-            let input_fname = args.value_of("INPUT").unwrap();
-            let markov_fname = args.value_of("MARKOV").unwrap();
-            let words = runner::get_wottas_fn(input_fname).expect("wottasquare data");
-            let markov_data = markov::read_markov(markov_fname).expect("markov data");
-            let markov_out = markov::make_beatnik(&words, &markov_data).expect("markov");
-            let output_fname = args.value_of("OUTPUT").expect("output name");
-            let mut buffer = File::create(output_fname).unwrap();
-            buffer.write(markov_out.as_bytes()).unwrap();
-        }
-        _ => {
-            println!("No command!\n");
-            println!("{}", str::from_utf8(&usage).unwrap());
+            single_stdin(args, &["INPUT", "MARKOV"])?;
+            let input_fname = required(args, "INPUT");
+            let markov_fname = required(args, "MARKOV");
+            let program = wotta_program(input_fname)?;
+            let markov_data = markov::read_markov(markov_fname)?;
+            let poetic_form = form::Form {
+                words_per_line: if args.is_present("LINE_WORDS") {
+                    Some(value_t!(args, "LINE_WORDS", usize)?)
+                } else {
+                    None
+                },
+                syllables: if args.is_present("SYLLABLES") {
+                    Some(values_t!(args, "SYLLABLES", usize)?)
+                } else {
+                    None
+                },
+                rhyme: args.value_of("RHYME").map(|scheme| scheme.chars().collect()),
+            };
+            let markov_out = if args.is_present("LINE_WORDS")
+                || args.is_present("SYLLABLES")
+                || args.is_present("RHYME")
+            {
+                form::make_poem(&program.words, &markov_data, &poetic_form)?
+            } else {
+                let max_padding = if args.is_present("PAD") {
+                    value_t!(args, "PAD", usize)?
+                } else {
+                    0
+                };
+                markov::make_beatnik_padded(&program.words, &markov_data, max_padding)?
+            };
+            let output_fname = required(args, "OUTPUT");
+            write_generated(output_fname, &markov_out, &program)?;
         }
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::markov;
-    use crate::runner;
-    use quickcheck::TestResult;
-
-    fn wotta_two_way(xs: Vec<u8>, source_words: String) -> Result<(), String> {
-        let mut info = String::new();
-        info.push_str(&format!("Source_words: {:?}\n", source_words));
-        let mut markov = markov::MarkovInfo::new();
-        let tokens = markov::get_tokens(&source_words).unwrap();
-        let mut last = markov::Token::Begin.string();
-        for token in tokens {
-            let str_token = token.string();
-            markov.add_token(last, &str_token);
-            last = str_token;
-        }
-        info.push_str(&format!("Markov: {:?}\n", markov));
-
-        let words = xs
-            .iter()
-            .filter(|x| *x != &0)
-            .map(|x| runner::Word {
-                score: *x,
-                word: String::from(""),
-            })
-            .collect();
-        info.push_str(&format!("Score in: {:?}\n", xs));
-        let markov_out = markov::make_beatnik(&words, &markov).unwrap();
-        info.push_str(&format!("Markov out: {:?}\n", markov_out));
-        let words_out = runner::get_words(&markov_out).unwrap();
-        info.push_str(&format!("Words out: {:?}\n", words_out));
-        let score_out: Vec<u8> = words_out.iter().map(|x| x.score).filter(|x| x != &0).collect();
-        info.push_str(&format!("Score out: {:?}\n", score_out));
-        let res = xs.into_iter().filter(|x| x != &0).collect::<Vec<u8>>() == score_out;
-        info.push_str(&format!("Res: {}\n", res));
-        if res {
-            Ok(())
-        } else {
-            Err(info)
+        ("template-beatnik", Some(args)) => {
+            single_stdin(args, &["INPUT", "MARKOV", "TEMPLATE"])?;
+            let input_fname = required(args, "INPUT");
+            let markov_fname = required(args, "MARKOV");
+            let template_fname = required(args, "TEMPLATE");
+            let program = wotta_program(input_fname)?;
+            let markov_data = markov::read_markov(markov_fname)?;
+            let template_text = peacenik::read_input(template_fname)?;
+            let template_out = in_file(
+                template_fname,
+                template::fill_template(&template_text, &program.words, &markov_data),
+            )?;
+            let output_fname = required(args, "OUTPUT");
+            write_generated(output_fname, &template_out, &program)?;
         }
-    }
-
-    #[test]
-    fn test_wotta_two_way_utf() {
-        wotta_two_way(vec![3], String::from("ŉb")).unwrap();
-    }
-
-    quickcheck! {
-        fn wotta_two_way_qc(xs: Vec<u8>, source_words: String) -> TestResult {
-            let res = wotta_two_way(xs, source_words);
-            match res {
-                Ok(_) => TestResult::passed(),
-                Err(info) => TestResult::error(info)
+        ("embed", Some(args)) => {
+            single_stdin(args, &["INPUT", "DOCUMENT", "MARKOV"])?;
+            let input_fname = required(args, "INPUT");
+            let document_fname = required(args, "DOCUMENT");
+            let markov_fname = required(args, "MARKOV");
+            let program = wotta_program(input_fname)?;
+            let markov_data = markov::read_markov(markov_fname)?;
+            let document = peacenik::read_input(document_fname)?;
+            let (embedded, changes) = in_file(
+                document_fname,
+                embed::embed(&document, &program.words, &markov_data),
+            )?;
+            eprintln!("Changed {} words", changes);
+            let output_fname = required(args, "OUTPUT");
+            write_output(output_fname, &embedded)?;
+        }
+        ("dict-beatnik", Some(args)) => {
+            single_stdin(args, &["INPUT", "DICTIONARY"])?;
+            let input_fname = required(args, "INPUT");
+            let dictionary_fname = required(args, "DICTIONARY");
+            let max_length = if args.is_present("MAX_LENGTH") {
+                Some(value_t!(args, "MAX_LENGTH", usize)?)
+            } else {
+                None
+            };
+            let filter = dictionary::DictionaryFilter {
+                max_length,
+                allowed_chars: args.value_of("CHARS").map(|c| c.to_lowercase().chars().collect()),
+                tags: args
+                    .values_of("TAGS")
+                    .map(|tags| tags.map(String::from).collect()),
+            };
+            let program = wotta_program(input_fname)?;
+            let dictionary = dictionary::Dictionary::read(dictionary_fname, &filter)?;
+            let dict_out = dictionary::make_beatnik(&program.words, &dictionary);
+            let output_fname = required(args, "OUTPUT");
+            write_generated(output_fname, &dict_out, &program)?;
+        }
+        ("fmt", Some(args)) => {
+            let input_fname = required(args, "INPUT");
+            let text = peacenik::read_input(input_fname)?;
+            let width = value_t!(args, "WIDTH", usize)?;
+            let options = format::FormatOptions {
+                width: if width == 0 { None } else { Some(width) },
+                sentence_per_line: args.is_present("SENTENCE_PER_LINE"),
+            };
+            write_output(required(args, "OUTPUT"), &format::reflow(&text, &options))?;
+        }
+        ("disassemble", Some(args)) => {
+            let program = source_program(args, args.is_present("WOTTASQUARE"))?;
+            write_output(required(args, "OUTPUT"), &asm::disassemble(&program))?;
+        }
+        ("assemble", Some(args)) => {
+            let input_fname = required(args, "INPUT");
+            let text = peacenik::read_input(input_fname)?;
+            let program = in_file(input_fname, asm::assemble(&text))?;
+            let mut wottasquare = Vec::new();
+            runner::write_wottasquare(&program, &mut wottasquare)?;
+            write_output(required(args, "OUTPUT"), &String::from_utf8_lossy(&wottasquare))?;
+        }
+        ("optimise", Some(args)) => {
+            let program = source_program(args, args.is_present("WOTTASQUARE"))?;
+            let optimised = transform::optimise(&program)?;
+            let mut wottasquare = Vec::new();
+            runner::write_wottasquare(&optimised, &mut wottasquare)?;
+            write_output(required(args, "OUTPUT"), &String::from_utf8_lossy(&wottasquare))?;
+        }
+        ("equiv", Some(args)) => {
+            single_stdin(args, &["A", "B", "INPUT_FILE"])?;
+            let a = any_program(required(args, "A"))?;
+            let b = any_program(required(args, "B"))?;
+            let inputs = equiv_inputs(args)?;
+            let instructions = equiv::compare_instructions(&a, &b);
+            let mut report = match instructions {
+                Some(ref difference) => format!("{}\n", difference),
+                None => String::from("Same instructions\n"),
+            };
+            // Running them decides it, if asked to
+            let differs = if inputs.is_empty() {
+                instructions.is_some()
+            } else {
+                let options = equiv::RunOptions {
+                    eof: value_t!(args, "EOF", runner::EofBehaviour)?,
+                    max_steps: value_t!(args, "MAX_STEPS", usize)?,
+                };
+                match equiv::compare_runs(&a, &b, &inputs, &options) {
+                    Some(difference) => {
+                        report += &format!("{}\n", difference);
+                        true
+                    }
+                    None => {
+                        report += &format!("Same behaviour on {} inputs\n", inputs.len());
+                        false
+                    }
+                }
+            };
+            write_output("-", &report)?;
+            if differs {
+                return Err(Failure::Differs);
+            }
+        }
+        ("lint", Some(args)) => {
+            let input_fname = required(args, "INPUT");
+            let text = peacenik::read_input(input_fname)?;
+            let lints = in_file(input_fname, lint::lint(&text))?;
+            let report: String = lints
+                .iter()
+                .map(|lint| format!("{}:{}\n", input_fname, lint))
+                .collect();
+            write_output("-", &report)?;
+            if !lints.is_empty() {
+                return Err(Failure::Lints(lints.len()));
             }
         }
+        _ => unreachable!("clap insists on a known subcommand"),
     }
+    Ok(())
 }
@@ -1,11 +1,12 @@
 use crate::common::{self, word_match};
+use crate::error::{Error, Result};
 use crate::runner;
-use rand;
 use rand::Rng;
-use serde_yaml;
-use std::collections::BTreeMap;
-use std::fs::File;
-use std::{io, str};
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::str::{self, FromStr};
 use unicode_normalization::UnicodeNormalization;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -14,12 +15,21 @@ pub enum Token {
     Comma,
     FullStop,
     QuestionMark,
+    Exclamation,
     OpenBracket,
     CloseBracket,
     Colon,
+    Semicolon,
+    Dash,
+    Ellipsis,
+    /// Straight double quotes, which could be opening or closing
     Quote,
+    OpenQuote,
+    CloseQuote,
     SingleQuote,
     Newline,
+    /// A blank line between paragraphs
+    Paragraph,
     Word(String),
     Begin,
 }
@@ -32,28 +42,69 @@ impl Token {
             Token::Comma => String::from(", "),
             Token::FullStop => String::from(". "),
             Token::QuestionMark => String::from("? "),
+            Token::Exclamation => String::from("! "),
             Token::OpenBracket => String::from("("),
-            Token::CloseBracket => String::from(")"),
+            Token::CloseBracket => String::from(") "),
             Token::Colon => String::from(": "),
+            Token::Semicolon => String::from("; "),
+            Token::Dash => String::from("— "),
+            Token::Ellipsis => String::from("... "),
             Token::Quote => String::from("\""),
+            Token::OpenQuote => String::from("“"),
+            Token::CloseQuote => String::from("” "),
             Token::SingleQuote => String::from("\'"),
             Token::Newline => String::from("\n"),
+            Token::Paragraph => String::from("\n\n"),
             Token::Begin => String::from(""),
         }
         .to_lowercase()
     }
+
+    /// Whether the next word starts a new sentence
+    fn ends_sentence(self: &Token) -> bool {
+        matches!(
+            self,
+            Token::Begin
+                | Token::FullStop
+                | Token::QuestionMark
+                | Token::Exclamation
+                | Token::Newline
+                | Token::Paragraph
+        )
+    }
+
+    /// Whether this opens a quote or bracket, which leaves the next word starting a sentence if this did
+    fn opens(self: &Token) -> bool {
+        matches!(self, Token::OpenQuote | Token::Quote | Token::OpenBracket)
+    }
+}
+
+/// Whitespace, which only matters if it has line breaks in
+fn whitespace_token(space: &str) -> Token {
+    match space.matches('\n').count() {
+        0 => Token::Junk,
+        1 => Token::Newline,
+        _ => Token::Paragraph,
+    }
 }
 
 named!(get_token<&str, Token>,
     alt!(
+        tag_s!("...") => {|_| Token::Ellipsis} |
+        tag_s!("…") => {|_| Token::Ellipsis} |
         tag_s!(".") => {|_| Token::FullStop} |
         tag_s!(",") => {|_| Token::Comma} |
         tag_s!(":") => {|_| Token::Colon} |
+        tag_s!(";") => {|_| Token::Semicolon} |
         tag_s!("?") => {|_| Token::QuestionMark} |
+        tag_s!("!") => {|_| Token::Exclamation} |
         tag_s!("(") => {|_| Token::OpenBracket} |
         tag_s!(")") => {|_| Token::CloseBracket} |
-        is_a_s!("\"“”") => {|_| Token::Quote} |
-        is_a_s!("\r\n") => {|_| Token::Newline} |
+        tag_s!("“") => {|_| Token::OpenQuote} |
+        tag_s!("”") => {|_| Token::CloseQuote} |
+        is_a_s!("\"") => {|_| Token::Quote} |
+        is_a_s!("-–—") => {|_| Token::Dash} |
+        is_a_s!(" \t\r\n") => { whitespace_token } |
         is_a_s!("\'") => {|_| Token::SingleQuote} |
         word_match => { |(begin, rest)| {
                 let mut word = String::from(begin);
@@ -69,19 +120,27 @@ fn empty_filter(x: Token) -> Option<Token> {
     Some(x)
 }
 
-pub fn get_tokens_fn(filename: &str) -> Result<Vec<Token>, io::Error> {
-    return common::get_words_core_fn(filename, get_token, empty_filter);
+/// Tokens parsed lazily from a reader, like runner::read_words
+pub fn read_tokens<'a>(
+    reader: Box<dyn BufRead + 'a>,
+    name: &str,
+    lossy: bool,
+) -> impl Iterator<Item = Result<Token>> + 'a {
+    common::Stream::new(reader, name, get_token, empty_filter, lossy)
 }
 
-#[cfg(test)]
-pub fn get_tokens(buffer: &str) -> Result<Vec<Token>, io::Error> {
-    return common::get_words_core(buffer, get_token, empty_filter);
+pub fn get_tokens_fn(filename: &str) -> Result<Vec<Token>> {
+    common::get_words_core_fn(filename, get_token, empty_filter)
+}
+
+pub fn get_tokens(buffer: &str) -> Result<Vec<Token>> {
+    common::get_words_core(buffer, get_token, empty_filter)
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MarkovSymbols {
-    count: u16,
-    tokens: BTreeMap<String, u16>,
+    count: u32,
+    tokens: BTreeMap<String, u32>,
 }
 
 impl MarkovSymbols {
@@ -92,9 +151,11 @@ impl MarkovSymbols {
         }
     }
 
-    fn add_token(self: &mut MarkovSymbols, token: &String) {
-        *self.tokens.entry(token.clone()).or_insert(0) += 1;
-        self.count += 1;
+    fn add_token(self: &mut MarkovSymbols, token: &str, count: u32) {
+        // Counts stop at the maximum for huge corpora, which only flattens the odds a little
+        let entry = self.tokens.entry(token.to_string()).or_insert(0);
+        *entry = entry.saturating_add(count);
+        self.count = self.count.saturating_add(count);
     }
 
     fn get_key(self: &MarkovSymbols) -> String {
@@ -109,7 +170,7 @@ impl MarkovSymbols {
         panic!("Didn't find token within range");
     }
 
-    fn count(self: &MarkovSymbols) -> u16 {
+    fn count(self: &MarkovSymbols) -> u32 {
         self.count
     }
 }
@@ -117,10 +178,31 @@ impl MarkovSymbols {
 #[derive(Serialize, Deserialize, Debug)]
 struct MarkovScores {
     tokens: BTreeMap<u8, MarkovSymbols>,
-    count: u16,
+    count: u32,
 }
 
-fn title_case(s: &str) -> String {
+/// Whether the next word in some prose starts a new sentence
+pub(crate) fn at_sentence_start(out: &str) -> bool {
+    let trimmed = out.trim_end_matches(' ');
+    trimmed.is_empty() || (trimmed.ends_with(|c: char| ".?!\n".contains(c)) && !trimmed.ends_with("..."))
+}
+
+/// Punctuation that goes straight after the previous word, rather than having a space before it
+fn attaches_to_previous(token: &str) -> bool {
+    token.starts_with(|c: char| ".,:;?!)”\n".contains(c))
+}
+
+/// Adds a generated token to some prose, with a space before it unless the punctuation on either side
+/// says otherwise
+pub(crate) fn push_token(out: &mut String, token: &str) {
+    let after_opener = out.ends_with(|c: char| c.is_whitespace() || c == '(' || c == '“');
+    if !out.is_empty() && !after_opener && !attaches_to_previous(token) {
+        out.push(' ');
+    }
+    out.push_str(token);
+}
+
+pub(crate) fn title_case(s: &str) -> String {
     let mut c = s.chars();
     match c.next() {
         None => String::new(),
@@ -139,6 +221,39 @@ fn title_case(s: &str) -> String {
     }
 }
 
+/// A word written differently, unless that would change its score
+fn keep_score(word: &str, cased: String) -> String {
+    if runner::score(&cased.to_lowercase()) == runner::score(&word.to_lowercase()) {
+        cased
+    } else {
+        debug!("Not writing '{}' as '{}' as that changes its score", word, cased);
+        word.to_string()
+    }
+}
+
+/// Uppercases the first letter of a word and leaves the rest alone, unlike title_case
+pub(crate) fn capitalise(word: &str) -> String {
+    let mut chars = word.chars();
+    let cased = match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    };
+    keep_score(word, cased)
+}
+
+/// Fallback for when there's no real word with the score we need
+pub(crate) fn made_up_word(score: u8) -> String {
+    warn!("Have no words with score {} so making up one", score);
+    let mut ret = String::new();
+    for _ in 0..(score / 10) {
+        ret.push('z');
+    }
+    for _ in 0..(score % 10) {
+        ret.push('a');
+    }
+    ret
+}
+
 impl MarkovScores {
     fn new() -> MarkovScores {
         MarkovScores {
@@ -151,38 +266,58 @@ impl MarkovScores {
         match self.tokens.get(score) {
             Some(score_hash) => {
                 let mut rng = rand::thread_rng();
-                let zero_score = self.tokens.get(&0).and_then(|x| Some(x.count())).unwrap_or(0);
+                let zero_score = self.tokens.get(&0).map(|x| x.count()).unwrap_or(0);
                 if zero_score == 0 {
                     return Some(score_hash.get_key());
                 }
                 let choice = rng.gen_range(0, self.count);
                 if choice < zero_score {
                     let zero_token = self.tokens.get(&0).unwrap().get_key();
-                    let mut rest = self.get_key(score).unwrap();
-                    if zero_token == Token::FullStop.string()
-                        || zero_token == Token::QuestionMark.string()
-                        || zero_token == Token::Newline.string()
-                    {
-                        rest = title_case(&rest);
-                    }
-                    Some(zero_token + &rest)
+                    Some(zero_token + &self.get_key(score).unwrap())
                 } else {
-                    let normal = score_hash.get_key();
-                    if normal == "i" {
-                        Some(String::from("I"))
-                    } else {
-                        Some(normal)
-                    }
+                    Some(score_hash.get_key())
                 }
             }
             None => None,
         }
     }
 
-    fn add_token(self: &mut MarkovScores, score: u8, token: &String) {
+    /// Like get_key, but only picking actual words rather than punctuation, from any score that passes
+    /// `wanted`, weighted by how often each was seen
+    fn get_word_where(self: &MarkovScores, wanted: &dyn Fn(u8) -> bool) -> Option<String> {
+        let candidates: Vec<(&String, u64)> = self
+            .tokens
+            .iter()
+            .filter(|(score, _)| wanted(**score))
+            .flat_map(|(_, symbols)| symbols.tokens.iter())
+            .filter(|(key, _)| key.chars().next().is_some_and(char::is_alphabetic))
+            .map(|(key, value)| (key, u64::from(*value)))
+            .collect();
+        let total: u64 = candidates.iter().map(|(_, value)| value).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut choice = rand::thread_rng().gen_range(0, total);
+        for (key, value) in candidates {
+            if choice < value {
+                return Some(key.clone());
+            }
+            choice -= value;
+        }
+        None
+    }
+
+    /// How likely the next token is to have this score
+    fn probability(self: &MarkovScores, score: u8) -> f64 {
+        self.tokens
+            .get(&score)
+            .map_or(0.0, |symbols| f64::from(symbols.count) / f64::from(self.count))
+    }
+
+    fn add_token(self: &mut MarkovScores, score: u8, token: &str, count: u32) {
         let score_entry = self.tokens.entry(score).or_insert(MarkovSymbols::new());
-        score_entry.add_token(token);
-        self.count += 1;
+        score_entry.add_token(token, count);
+        self.count = self.count.saturating_add(count);
     }
 }
 
@@ -190,6 +325,15 @@ impl MarkovScores {
 pub struct MarkovInfo {
     scores: MarkovScores,
     lookup: BTreeMap<String, MarkovScores>,
+    /// How often each word was seen written each way, away from the start of a sentence
+    #[serde(default)]
+    casing: BTreeMap<String, BTreeMap<String, u32>>,
+}
+
+impl Default for MarkovInfo {
+    fn default() -> MarkovInfo {
+        MarkovInfo::new()
+    }
 }
 
 impl MarkovInfo {
@@ -197,34 +341,98 @@ impl MarkovInfo {
         MarkovInfo {
             scores: MarkovScores::new(),
             lookup: BTreeMap::new(),
+            casing: BTreeMap::new(),
         }
     }
 
-    pub fn add_token(self: &mut MarkovInfo, last: String, token: &String) {
+    /// Records how a word was written in the corpus
+    pub fn add_casing(self: &mut MarkovInfo, word: &str) {
+        let count = self
+            .casing
+            .entry(word.to_lowercase())
+            .or_default()
+            .entry(word.to_string())
+            .or_insert(0);
+        *count = count.saturating_add(1);
+    }
+
+    /// Writes the word in a generated token the way the corpus mostly did (e.g. names, acronyms and
+    /// "I"), and capitalises it if it starts a sentence. The score never changes.
+    pub(crate) fn recase(self: &MarkovInfo, token: &str, sentence_start: bool) -> String {
+        let start = token.find(char::is_alphabetic).unwrap_or(token.len());
+        let (prefix, word) = token.split_at(start);
+        let dominant = self
+            .casing
+            .get(&word.to_lowercase())
+            .and_then(|forms| forms.iter().max_by_key(|(_, count)| **count));
+        let mut cased = match dominant {
+            Some((form, _)) => keep_score(word, form.clone()),
+            None => word.to_string(),
+        };
+        if sentence_start || (!prefix.is_empty() && at_sentence_start(prefix)) {
+            cased = capitalise(&cased);
+        }
+        format!("{}{}", prefix, cased)
+    }
+
+    pub fn add_token(self: &mut MarkovInfo, last: String, token: &str) {
+        self.add_tokens(last, token, 1);
+    }
+
+    /// Records `token` following `last` this many times
+    fn add_tokens(self: &mut MarkovInfo, last: String, token: &str, count: u32) {
         let token_score = runner::score(&token.to_lowercase());
-        let last_hash = self.lookup.entry(last.clone()).or_insert(MarkovScores::new());
-        last_hash.add_token(token_score, token);
-        self.scores.add_token(token_score, token);
+        let last_hash = self.lookup.entry(last).or_insert(MarkovScores::new());
+        last_hash.add_token(token_score, token, count);
+        self.scores.add_token(token_score, token, count);
     }
 
     fn default_get(self: &MarkovInfo, score: u8) -> String {
         match self.scores.get_key(&score) {
             Some(score_hash) => score_hash,
-            None => {
-                warn!("Have no words with score {} so making up one", score);
-                let mut ret = String::new();
-                for _ in 0..(score / 10) {
-                    ret.push('z');
+            None => made_up_word(score),
+        }
+    }
+
+    /// A single word whose score passes `wanted`, preferring ones that follow `last` in the corpus
+    pub(crate) fn get_word_where(
+        self: &MarkovInfo,
+        last: &str,
+        wanted: &dyn Fn(u8) -> bool,
+    ) -> Option<String> {
+        self.lookup
+            .get(last)
+            .and_then(|scores| scores.get_word_where(wanted))
+            .or_else(|| self.scores.get_word_where(wanted))
+    }
+
+    /// A single word with exactly this score, preferring ones that follow `last` in the corpus
+    pub(crate) fn get_word(self: &MarkovInfo, last: &str, score: u8) -> Option<String> {
+        self.get_word_where(last, &|s| s == score)
+    }
+
+    /// A no-op word to put after `last` if going through it makes a word with `score` more likely than
+    /// going straight there, picking the one giving the most probable path
+    fn padding_word(self: &MarkovInfo, last: &str, score: u8) -> Option<String> {
+        let following = self.lookup.get(last)?;
+        let direct = following.probability(score);
+        let mut best: Option<(f64, &String)> = None;
+        for (_, symbols) in following.tokens.iter().filter(|(s, _)| runner::is_nop(**s)) {
+            for (word, count) in symbols.tokens.iter() {
+                if !word.starts_with(char::is_alphabetic) {
+                    continue;
                 }
-                for _ in 0..(score % 10) {
-                    ret.push('a');
+                let via = f64::from(*count) / f64::from(following.count)
+                    * self.lookup.get(word).map_or(0.0, |next| next.probability(score));
+                if via > direct && best.is_none_or(|(p, _)| via > p) {
+                    best = Some((via, word));
                 }
-                return ret;
             }
         }
+        best.map(|(_, word)| word.clone())
     }
 
-    fn get_token(self: &MarkovInfo, last: &String, score: u8) -> String {
+    pub(crate) fn get_token(self: &MarkovInfo, last: &str, score: u8) -> String {
         debug!("Looking up for '{}' and {}", last, score);
         match self.lookup.get(last) {
             Some(word) => match word.get_key(&score) {
@@ -236,55 +444,468 @@ impl MarkovInfo {
     }
 }
 
-pub fn generate_markov<'a>(filename: &str) -> Result<MarkovInfo, io::Error> {
-    let tokens = get_tokens_fn(filename)?;
-    let str_tokens = tokens
-        .into_iter()
-        .filter(
-            |t: &Token| -> bool {
-                if let &Token::Junk = t {
-                    false
+fn is_word(token: &str) -> bool {
+    token.starts_with(char::is_alphabetic)
+}
+
+/// Command scores with fewer different words than this are reported as poorly covered
+const POOR_COVERAGE: usize = 3;
+
+/// How many of the most common words to list for each command
+const TOP_WORDS: usize = 5;
+
+/// Summary of what's in a model, to judge whether a corpus is any good for Beatnik
+#[derive(Debug)]
+pub struct MarkovStats {
+    /// Different words, not counting punctuation
+    pub vocabulary: usize,
+    pub tokens: u64,
+    /// Different tokens and total count for each score that has any
+    pub per_score: BTreeMap<u8, (usize, u32)>,
+    pub contexts: usize,
+    /// Mean number of different tokens seen after each context
+    pub branching: f64,
+    /// Most common words for each command score, most frequent first
+    pub top_words: BTreeMap<u8, Vec<(String, u32)>>,
+}
+
+impl MarkovStats {
+    /// Command scores with few or no words to pick from
+    pub fn poorly_covered(self: &MarkovStats) -> Vec<u8> {
+        (5..=17)
+            .filter(|score| self.per_score.get(score).map_or(0, |(words, _)| *words) < POOR_COVERAGE)
+            .collect()
+    }
+}
+
+impl fmt::Display for MarkovStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Vocabulary: {} words, {} tokens", self.vocabulary, self.tokens)?;
+        writeln!(
+            f,
+            "Contexts: {}, average branching {:.2}",
+            self.contexts, self.branching
+        )?;
+        writeln!(f, "Tokens per score:")?;
+        for (score, (different, count)) in &self.per_score {
+            writeln!(
+                f,
+                "  {:3} {:?}: {} different, {} total",
+                score,
+                runner::action(*score),
+                different,
+                count
+            )?;
+        }
+        let poor = self.poorly_covered();
+        if !poor.is_empty() {
+            writeln!(f, "Poorly covered commands:")?;
+            for score in poor {
+                let different = self.per_score.get(&score).map_or(0, |(words, _)| *words);
+                writeln!(
+                    f,
+                    "  {:3} {:?}: {} different",
+                    score,
+                    runner::action(score),
+                    different
+                )?;
+            }
+        }
+        writeln!(f, "Most common words per command:")?;
+        for (score, words) in &self.top_words {
+            let listed: Vec<String> = words
+                .iter()
+                .map(|(word, count)| format!("{} ({})", word, count))
+                .collect();
+            writeln!(
+                f,
+                "  {:3} {:?}: {}",
+                score,
+                runner::action(*score),
+                listed.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl MarkovInfo {
+    pub fn stats(self: &MarkovInfo) -> MarkovStats {
+        let per_score = self
+            .scores
+            .tokens
+            .iter()
+            .map(|(score, symbols)| (*score, (symbols.tokens.len(), symbols.count)))
+            .collect();
+        let top_words = (5..=17)
+            .filter_map(|score| self.scores.tokens.get(&score).map(|symbols| (score, symbols)))
+            .map(|(score, symbols)| {
+                let mut words: Vec<(String, u32)> = symbols
+                    .tokens
+                    .iter()
+                    .filter(|(token, _)| is_word(token))
+                    .map(|(token, count)| (token.clone(), *count))
+                    .collect();
+                words.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+                words.truncate(TOP_WORDS);
+                (score, words)
+            })
+            .collect();
+        let branches: usize = self
+            .lookup
+            .values()
+            .map(|following| {
+                following
+                    .tokens
+                    .values()
+                    .map(|symbols| symbols.tokens.len())
+                    .sum::<usize>()
+            })
+            .sum();
+        MarkovStats {
+            vocabulary: self
+                .scores
+                .tokens
+                .values()
+                .map(|symbols| symbols.tokens.keys().filter(|token| is_word(token)).count())
+                .sum(),
+            tokens: self
+                .scores
+                .tokens
+                .values()
+                .map(|symbols| u64::from(symbols.count))
+                .sum(),
+            per_score,
+            contexts: self.lookup.len(),
+            branching: if self.lookup.is_empty() {
+                0.0
+            } else {
+                branches as f64 / self.lookup.len() as f64
+            },
+            top_words,
+        }
+    }
+}
+
+/// Which words to keep when pruning a model. Punctuation is always kept.
+#[derive(Debug, Default)]
+pub struct PruneOptions {
+    /// Drop words seen fewer times than this
+    pub min_count: u32,
+    /// Only keep words on this list
+    pub allowed: Option<BTreeSet<String>>,
+    /// Only keep words this matches
+    pub pattern: Option<Regex>,
+    /// Never keep these e.g. profanity
+    pub excluded: BTreeSet<String>,
+    /// Lowercase and NFKC-normalise words, merging ones that end up the same
+    pub normalise: bool,
+}
+
+impl PruneOptions {
+    fn accepts(self: &PruneOptions, word: &str, count: u32) -> bool {
+        count >= self.min_count
+            && self.allowed.as_ref().is_none_or(|allowed| allowed.contains(word))
+            && self.pattern.as_ref().is_none_or(|pattern| pattern.is_match(word))
+            && !self.excluded.contains(word)
+    }
+}
+
+/// Reads a list of words, one per line, as used for PruneOptions
+pub fn read_word_list(filename: &str) -> Result<BTreeSet<String>> {
+    let text = common::read_input(filename)?;
+    Ok(text
+        .lines()
+        .map(|line| line.trim().to_lowercase())
+        .filter(|word| !word.is_empty())
+        .collect())
+}
+
+impl MarkovInfo {
+    /// A copy of the model with tokens renamed or dropped. What followed a dropped token still counts
+    /// towards the overall scores, just not as following anything in particular.
+    fn rebuild(self: &MarkovInfo, rename: &dyn Fn(&str) -> Option<String>) -> MarkovInfo {
+        let mut res = MarkovInfo::new();
+        for (last, following) in &self.lookup {
+            let last = rename(last);
+            for symbols in following.tokens.values() {
+                for (token, count) in &symbols.tokens {
+                    let token = match rename(token) {
+                        Some(token) => token,
+                        None => continue,
+                    };
+                    match last {
+                        Some(ref last) => res.add_tokens(last.clone(), &token, *count),
+                        None => {
+                            let token_score = runner::score(&token.to_lowercase());
+                            res.scores.add_token(token_score, &token, *count);
+                        }
+                    }
+                }
+            }
+        }
+        for (word, forms) in &self.casing {
+            if let Some(word) = rename(word) {
+                let entry = res.casing.entry(word).or_default();
+                for (form, count) in forms {
+                    let total = entry.entry(form.clone()).or_insert(0);
+                    *total = total.saturating_add(*count);
+                }
+            }
+        }
+        res
+    }
+
+    /// Drops words the options don't want, but always leaves at least one word for every command
+    /// if it can, by keeping the most common otherwise-dropped word that isn't excluded
+    pub fn prune(self: &MarkovInfo, options: &PruneOptions) -> MarkovInfo {
+        let normalised = if options.normalise {
+            self.rebuild(&|token| {
+                Some(if is_word(token) {
+                    token.nfkc().collect::<String>().to_lowercase()
                 } else {
-                    true
+                    token.to_string()
+                })
+            })
+        } else {
+            self.rebuild(&|token| Some(token.to_string()))
+        };
+
+        let mut keep = BTreeSet::new();
+        for (score, symbols) in &normalised.scores.tokens {
+            let words = symbols.tokens.iter().filter(|(token, _)| is_word(token));
+            let before = keep.len();
+            keep.extend(
+                words
+                    .clone()
+                    .filter(|(token, count)| options.accepts(token, **count))
+                    .map(|(token, _)| token.clone()),
+            );
+            if keep.len() > before || runner::is_nop(*score) {
+                continue;
+            }
+            match words
+                .filter(|(token, _)| !options.excluded.contains(*token))
+                .max_by_key(|(_, count)| **count)
+            {
+                Some((token, _)) => {
+                    info!(
+                        "Keeping '{}' so {:?} still has a word",
+                        token,
+                        runner::action(*score)
+                    );
+                    keep.insert(token.clone());
                 }
-            },
-        )
-        .map(|t| t.string());
+                None => warn!("No words left for {:?}", runner::action(*score)),
+            }
+        }
+        normalised.rebuild(&|token| {
+            if !is_word(token) || keep.contains(token) {
+                Some(token.to_string())
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Builds a model from already tokenised text
+pub fn build_markov(tokens: Vec<Token>) -> MarkovInfo {
+    build_markov_from(tokens.into_iter().map(Ok)).expect("tokens are already parsed")
+}
+
+/// Builds a model from a stream of tokens, so the whole corpus never has to be in memory at once
+pub fn build_markov_from<I: IntoIterator<Item = Result<Token>>>(tokens: I) -> Result<MarkovInfo> {
     let mut res = MarkovInfo::new();
     let mut last = Token::Begin.string();
-    for token in str_tokens {
+    let mut sentence_start = true;
+    for token in tokens {
+        let token = token?;
+        if token == Token::Junk {
+            continue;
+        }
+        if let Token::Word(ref word) = token {
+            // Everything's capitalised at the start of a sentence, so that says nothing about the word
+            if !sentence_start {
+                res.add_casing(word);
+            }
+        }
+        sentence_start = token.ends_sentence() || (sentence_start && token.opens());
+        let token = token.string();
         res.add_token(last, &token);
-        last = token.clone();
+        last = token;
     }
     Ok(res)
 }
 
-pub fn read_markov(markov_fname: &str) -> Result<MarkovInfo, io::Error> {
-    let buffer = File::open(markov_fname).unwrap();
-    Ok(serde_yaml::from_reader(&buffer).map_err(common::io_str_error)?)
+/// Builds a model from a corpus file, or stdin for "-"
+pub fn generate_markov(filename: &str, lossy: bool) -> Result<MarkovInfo> {
+    build_markov_from(read_tokens(common::open_input(filename)?, filename, lossy))
+}
+
+/// Start of a binary model file, so it can be told apart from the text formats
+const BINARY_PREFIX: &[u8] = b"PEACENIK-MARKOV";
+
+/// The binary prefix and a version byte, which went up when counts grew from 16 to 32 bits. Text models
+/// from before then still load, as their numbers just widen.
+const BINARY_MAGIC: &[u8] = b"PEACENIK-MARKOV\x01";
+
+/// How a Markov model is stored on disk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModelFormat {
+    Yaml,
+    Json,
+    /// Compact bincode encoding, for big corpora
+    Binary,
+}
+
+impl FromStr for ModelFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<ModelFormat, String> {
+        match s {
+            "yaml" => Ok(ModelFormat::Yaml),
+            "json" => Ok(ModelFormat::Json),
+            "binary" => Ok(ModelFormat::Binary),
+            _ => Err(format!(
+                "Unknown model format '{}', expected yaml, json or binary",
+                s
+            )),
+        }
+    }
+}
+
+impl ModelFormat {
+    /// Guesses the format from a filename's extension, defaulting to YAML
+    pub fn from_filename(filename: &str) -> ModelFormat {
+        if filename.ends_with(".json") {
+            ModelFormat::Json
+        } else if filename.ends_with(".bin") {
+            ModelFormat::Binary
+        } else {
+            ModelFormat::Yaml
+        }
+    }
+
+    /// Works out which format some model data is in from its contents
+    pub fn detect(data: &[u8]) -> ModelFormat {
+        if data.starts_with(BINARY_PREFIX) {
+            ModelFormat::Binary
+        } else if data.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{') {
+            ModelFormat::Json
+        } else {
+            ModelFormat::Yaml
+        }
+    }
+}
+
+/// Decodes a model in any of the formats, working out which one it is
+pub fn parse_markov(data: &[u8]) -> Result<MarkovInfo> {
+    let format = ModelFormat::detect(data);
+    let result = match format {
+        ModelFormat::Yaml => serde_yaml::from_slice(data).map_err(|e| e.to_string()),
+        ModelFormat::Json => serde_json::from_slice(data).map_err(|e| e.to_string()),
+        ModelFormat::Binary if !data.starts_with(BINARY_MAGIC) => Err(String::from(
+            "made by another version of peacenik, so generate it again",
+        )),
+        ModelFormat::Binary => bincode::deserialize(&data[BINARY_MAGIC.len()..]).map_err(|e| e.to_string()),
+    };
+    result.map_err(|e| Error::Model(format!("Corrupt {:?} model: {}", format, e)))
+}
+
+/// Encodes a model in the given format
+pub fn serialize_markov(markov: &MarkovInfo, format: ModelFormat) -> Result<Vec<u8>> {
+    let result = match format {
+        ModelFormat::Yaml => serde_yaml::to_string(markov)
+            .map(String::into_bytes)
+            .map_err(|e| e.to_string()),
+        ModelFormat::Json => serde_json::to_vec(markov).map_err(|e| e.to_string()),
+        ModelFormat::Binary => bincode::serialize(markov)
+            .map(|data| [BINARY_MAGIC, &data].concat())
+            .map_err(|e| e.to_string()),
+    };
+    result.map_err(|e| Error::Model(format!("Can't encode model as {:?}: {}", format, e)))
+}
+
+/// Reads a model in any format from a file, or stdin for "-"
+pub fn read_markov(markov_fname: &str) -> Result<MarkovInfo> {
+    let mut data = Vec::new();
+    common::open_input(markov_fname)?
+        .read_to_end(&mut data)
+        .map_err(|e| common::named_io_error(markov_fname, e))?;
+    parse_markov(&data).map_err(|e| match e {
+        Error::Model(msg) => Error::Model(format!("{} in {}", msg, markov_fname)),
+        other => other,
+    })
+}
+
+/// Writes a model to a file, or stdout for "-"
+pub fn write_markov(markov_fname: &str, markov: &MarkovInfo, format: ModelFormat) -> Result<()> {
+    let data = serialize_markov(markov, format)?;
+    let mut writer = common::open_output(markov_fname)?;
+    writer
+        .write_all(&data)
+        .and_then(|_| writer.flush())
+        .map_err(|e| common::named_io_error(markov_fname, e))
+}
+
+pub fn make_beatnik(words: &[runner::Word], markov: &MarkovInfo) -> Result<String> {
+    make_beatnik_padded(words, markov, 0)
+}
+
+/// The lookup key for what follows a generated token, which may have punctuation in front of the word
+pub(crate) fn context(token: &str) -> String {
+    let word = token.trim_start_matches(|c: char| !c.is_alphabetic());
+    if word.is_empty() {
+        token.to_string()
+    } else {
+        word.to_lowercase()
+    }
 }
 
-pub fn make_beatnik(words: &Vec<runner::Word>, markov: &MarkovInfo) -> Result<String, io::Error> {
+/// Like make_beatnik, but puts up to `max_padding` no-op words before each program word when they make
+/// for a more probable path through the model. Padding only goes where runner::nop_gaps allows it, so
+/// operands and skip distances are left alone.
+pub fn make_beatnik_padded(
+    words: &[runner::Word],
+    markov: &MarkovInfo,
+    max_padding: usize,
+) -> Result<String> {
+    let gaps = runner::nop_gaps(words);
     let mut last = Token::Begin.string();
     let mut out = String::new();
-    for word in words {
-        let mut token = markov.get_token(&last, word.score());
-        if last == "" {
-            token = title_case(&token);
-        } else if !token.starts_with(".") && !token.starts_with("?") && !token.starts_with(",") {
-            out.push_str(" ");
+    for (index, word) in words.iter().enumerate() {
+        if gaps[index] {
+            for _ in 0..max_padding {
+                let padding = match markov.padding_word(&context(&last), word.score()) {
+                    Some(padding) => padding,
+                    None => break,
+                };
+                debug!("Padding with '{}' before word {}", padding, index);
+                let padding = markov.recase(&padding, at_sentence_start(&out));
+                push_token(&mut out, &padding);
+                last = padding;
+            }
         }
-
-        out.push_str(&token);
+        let token = markov.recase(
+            &markov.get_token(&context(&last), word.score()),
+            at_sentence_start(&out),
+        );
+        push_token(&mut out, &token);
         last = token;
     }
-    return Ok(out);
+    Ok(out)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{empty_filter, get_token};
+    use super::{
+        build_markov, capitalise, empty_filter, get_token, get_tokens, make_beatnik_padded, parse_markov,
+        push_token, read_markov, read_tokens, serialize_markov, MarkovInfo, ModelFormat, PruneOptions, Token,
+    };
     use crate::common;
+    use crate::runner;
+    use crate::template::check_prose;
     use quickcheck::TestResult;
 
     #[test]
@@ -292,6 +913,186 @@ mod tests {
         common::get_words_core("why—I", get_token, empty_filter).unwrap();
     }
 
+    #[test]
+    fn test_punctuation_tokens() {
+        let tokens: Vec<Token> = get_tokens("Wait... “Yes!” she said; then - no.\n\nEnd\n")
+            .unwrap()
+            .into_iter()
+            .filter(|t| *t != Token::Junk)
+            .collect();
+        let word = |w: &str| Token::Word(String::from(w));
+        assert_eq!(
+            tokens,
+            vec![
+                word("Wait"),
+                Token::Ellipsis,
+                Token::OpenQuote,
+                word("Yes"),
+                Token::Exclamation,
+                Token::CloseQuote,
+                word("she"),
+                word("said"),
+                Token::Semicolon,
+                word("then"),
+                Token::Dash,
+                word("no"),
+                Token::FullStop,
+                Token::Paragraph,
+                word("End"),
+                Token::Newline,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_streaming_keeps_paragraphs() {
+        let text = "Word\n\n".repeat(20_000);
+        let tokens: Vec<Token> = read_tokens(Box::new(text.as_bytes()), "test", false)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(tokens.iter().filter(|t| **t == Token::Paragraph).count(), 20_000);
+        assert!(!tokens.contains(&Token::Newline));
+    }
+
+    #[test]
+    fn test_spacing() {
+        let mut out = String::new();
+        for token in &[
+            "“", "Hi", "” ", "she", "said", "(", "softly", ") ", "— ", "then", "... ", "left", ". ",
+        ] {
+            push_token(&mut out, token);
+        }
+        assert_eq!(out, "“Hi” she said (softly) — then... left. ");
+    }
+
+    #[test]
+    fn test_proper_nouns() {
+        let model = build_markov(get_tokens("I met Hamlet. Hamlet met me, and Hamlet left.").unwrap());
+        assert_eq!(model.recase("hamlet", false), "Hamlet");
+        assert_eq!(model.recase(", hamlet", false), ", Hamlet");
+        assert_eq!(model.recase("met", false), "met");
+        assert_eq!(model.recase(". met", false), ". Met");
+        assert_eq!(model.recase("met", true), "Met");
+    }
+
+    #[test]
+    fn test_casing() {
+        let model =
+            build_markov(get_tokens("So NATO and I met McBeth. Then I and NATO and i left.").unwrap());
+        assert_eq!(model.recase("nato", false), "NATO");
+        assert_eq!(model.recase("i", false), "I");
+        assert_eq!(model.recase("mcbeth", true), "McBeth");
+        // A quote or bracket at the start of a sentence doesn't stop the next word being capitalised for it
+        let model = build_markov(
+            get_tokens("He said. “The cat.” He said. (The dog.) I saw the cat. He said. \"The end.\"")
+                .unwrap(),
+        );
+        assert_eq!(model.recase("the", false), "the");
+        // Capital ß is SS, which would score differently
+        assert_eq!(capitalise("ßa"), "ßa");
+        assert_eq!(capitalise("ähnlich"), "Ähnlich");
+    }
+
+    #[test]
+    fn test_model_formats() {
+        let model = build_markov(get_tokens("The cat sat on the mat. The dog sat too!").unwrap());
+        let yaml = serialize_markov(&model, ModelFormat::Yaml).unwrap();
+        for format in &[ModelFormat::Yaml, ModelFormat::Json, ModelFormat::Binary] {
+            let data = serialize_markov(&model, *format).unwrap();
+            assert_eq!(ModelFormat::detect(&data), *format);
+            let parsed = parse_markov(&data).unwrap();
+            assert_eq!(serialize_markov(&parsed, ModelFormat::Yaml).unwrap(), yaml);
+        }
+        assert!(serialize_markov(&model, ModelFormat::Binary).unwrap().len() < yaml.len());
+    }
+
+    #[test]
+    fn test_bad_models() {
+        let err = read_markov("/nonexistent/model.yaml").unwrap_err().to_string();
+        assert!(err.contains("/nonexistent/model.yaml"), "{}", err);
+        let err = parse_markov(b"{\"scores\": [").unwrap_err().to_string();
+        assert!(err.contains("Corrupt Json model"), "{}", err);
+        let mut binary = serialize_markov(&build_markov(Vec::new()), ModelFormat::Binary).unwrap();
+        binary.truncate(binary.len() - 1);
+        assert!(parse_markov(&binary).is_err());
+        // Binary models from when counts were 16 bits can't be read, though text ones can
+        let err = parse_markov(b"PEACENIK-MARKOV\0\0\0").unwrap_err().to_string();
+        assert!(err.contains("another version"), "{}", err);
+        let model =
+            parse_markov(b"{\"scores\": {\"tokens\": {}, \"count\": 70000}, \"lookup\": {}}").unwrap();
+        assert_eq!(model.scores.count, 70_000);
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = build_markov(get_tokens("The cat sat on the mat. The dog sat too!").unwrap()).stats();
+        assert_eq!(stats.vocabulary, 7);
+        assert_eq!(stats.tokens, 12);
+        // "cat", "dog" and "mat" are all PUSH
+        assert_eq!(stats.per_score.get(&5), Some(&(3, 3)));
+        assert_eq!(stats.top_words[&6], vec![(String::from("the"), 3)]);
+        assert!(!stats.poorly_covered().contains(&5));
+        assert!(stats.poorly_covered().contains(&17));
+        assert_eq!(stats.contexts, 9);
+    }
+
+    #[test]
+    fn test_prune() {
+        let model = build_markov(get_tokens("The cat sat on the mat. The cat sat on the cat.").unwrap());
+        let options = PruneOptions {
+            min_count: 2,
+            excluded: vec![String::from("sat")].into_iter().collect(),
+            ..PruneOptions::default()
+        };
+        let stats = model.prune(&options).stats();
+        // "mat" only appears once, and "on" is a no-op so doesn't need keeping
+        assert_eq!(stats.top_words[&5], vec![(String::from("cat"), 3)]);
+        assert!(!stats.top_words.contains_key(&3));
+        // Nothing else is DISCARD, so "the" is kept despite the word list
+        let options = PruneOptions {
+            allowed: Some(vec![String::from("cat")].into_iter().collect()),
+            ..PruneOptions::default()
+        };
+        let stats = model.prune(&options).stats();
+        assert_eq!(stats.top_words[&6].len(), 1);
+        assert_eq!(stats.vocabulary, 2);
+    }
+
+    #[test]
+    fn test_normalise() {
+        let mut model = MarkovInfo::new();
+        model.add_token(String::new(), "ﬁsh");
+        model.add_token(String::from("ﬁsh"), ". ");
+        model.add_token(String::from(". "), "fish");
+        let options = PruneOptions {
+            normalise: true,
+            ..PruneOptions::default()
+        };
+        let stats = model.prune(&options).stats();
+        assert_eq!(stats.vocabulary, 1);
+        assert_eq!(stats.tokens, 3);
+    }
+
+    #[test]
+    fn test_padding() {
+        // "ha" (PUSH) only ever follows "so", which only ever follows "it"
+        let model = build_markov(get_tokens("it so ha. it so ha.").unwrap());
+        let program = runner::get_wottas("[2]\n[5:PUSH]\n").unwrap();
+        assert_eq!(make_beatnik_padded(&program, &model, 2).unwrap(), "It so ha");
+    }
+
+    #[test]
+    fn test_padding_skips_operands() {
+        let model = build_markov(get_tokens("it so ha. it so ha.").unwrap());
+        let program = runner::get_wottas("[5:PUSH]\n[2]\n[5:PUSH]\n[2]\n").unwrap();
+        for _ in 0..10 {
+            let prose = runner::get_words(&make_beatnik_padded(&program, &model, 3).unwrap()).unwrap();
+            assert_eq!(prose[1].score, 2);
+            assert_eq!(prose[prose.len() - 1].score, 2);
+            check_prose(&prose, &program).unwrap();
+        }
+    }
+
     quickcheck! {
         fn token_test(xs: String) -> TestResult {
             return match common::get_words_core(&xs, get_token, empty_filter) {
@@ -0,0 +1,179 @@
+use crate::error::Result;
+use crate::runner::{self, EofBehaviour, Input, Machine, Output, StackOptions, Word};
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Beatnik,
+    Wottasquare,
+}
+
+const HELP: &str = "Type Beatnik prose to run it a line at a time. Commands:
+  :wotta    switch to Wottasquare input ([score:comment])
+  :beatnik  switch back to Beatnik input
+  :undo     undo the last line
+  :stack    show the stack
+  :help     show this message
+  :quit     leave the REPL";
+
+pub struct Repl {
+    machine: Machine,
+    history: Vec<Machine>,
+    mode: Mode,
+}
+
+impl Repl {
+    pub fn new(options: StackOptions) -> Repl {
+        Repl {
+            machine: Machine::with_options(options),
+            history: Vec::new(),
+            mode: Mode::Beatnik,
+        }
+    }
+
+    pub fn parse(self: &Repl, line: &str) -> Result<Vec<Word>> {
+        match self.mode {
+            Mode::Beatnik => runner::get_words(line),
+            // A final wotta without its newline is treated as incomplete by the parser
+            Mode::Wottasquare => runner::get_wottas(&format!("{}\n", line.trim())),
+        }
+    }
+
+    /// Runs one line against the persistent stack, returning what it output.
+    /// A line that fails leaves the stack as it was beforehand.
+    pub fn execute(self: &mut Repl, words: &[Word], input: &mut Input) -> Result<Vec<u8>> {
+        let before = self.machine.clone();
+        let mut printed = Vec::new();
+        let result = self
+            .machine
+            .run(words, input, &mut Output::new(Box::new(&mut printed), false));
+        match result {
+            Ok(()) => {
+                self.history.push(before);
+                Ok(printed)
+            }
+            Err(err) => {
+                self.machine = before;
+                Err(err)
+            }
+        }
+    }
+
+    pub fn undo(self: &mut Repl) -> bool {
+        match self.history.pop() {
+            Some(machine) => {
+                self.machine = machine;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn stack(self: &Repl) -> &[i64] {
+        &self.machine.stack
+    }
+}
+
+fn describe(words: &[Word]) -> String {
+    words
+        .iter()
+        .map(|w| format!("{}={} ({:?})", w.word, w.score, runner::action(w.score)))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+pub fn run_repl(options: StackOptions) -> Result<()> {
+    let mut input = Input::stdin(EofBehaviour::Zero, true);
+    let mut repl = Repl::new(options);
+    println!("{}", HELP);
+    loop {
+        match repl.mode {
+            Mode::Beatnik => print!("beatnik> "),
+            Mode::Wottasquare => print!("wotta> "),
+        }
+        io::stdout().flush()?;
+        let line = match input.read_line()? {
+            Some(line) => line,
+            None => break,
+        };
+        match line.trim() {
+            "" => continue,
+            ":quit" | ":q" => break,
+            ":help" => println!("{}", HELP),
+            ":wotta" | ":wottasquare" => repl.mode = Mode::Wottasquare,
+            ":beatnik" => repl.mode = Mode::Beatnik,
+            ":stack" => println!("Stack: {:?}", repl.stack()),
+            ":undo" => {
+                if repl.undo() {
+                    println!("Stack: {:?}", repl.stack());
+                } else {
+                    println!("Nothing to undo");
+                }
+            }
+            _ => {
+                let result = repl.parse(&line).and_then(|words| {
+                    println!("{}", describe(&words));
+                    repl.execute(&words, &mut input)
+                });
+                match result {
+                    Ok(printed) => {
+                        if !printed.is_empty() {
+                            println!("Output: {}", String::from_utf8_lossy(&printed));
+                        }
+                        println!("Stack: {:?}", repl.stack());
+                    }
+                    Err(err) => println!("Error: {}", err),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mode, Repl};
+    use crate::runner::{EofBehaviour, Input, StackOptions};
+
+    fn execute(repl: &mut Repl, line: &str) -> Result<Vec<u8>, String> {
+        let mut input = Input::new(Box::new(&b""[..]), EofBehaviour::Zero, true);
+        let words = repl.parse(line).map_err(|e| e.to_string())?;
+        repl.execute(&words, &mut input).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_stack_persists_between_lines() {
+        let mut repl = Repl::new(StackOptions::default());
+        // "Ha" is PUSH and "Trace" is ADD
+        execute(&mut repl, "Ha ghosts").unwrap();
+        assert_eq!(repl.stack(), &[10]);
+        execute(&mut repl, "Ha lots").unwrap();
+        assert_eq!(repl.stack(), &[10, 4]);
+        execute(&mut repl, "Trace").unwrap();
+        assert_eq!(repl.stack(), &[14]);
+    }
+
+    #[test]
+    fn test_undo() {
+        let mut repl = Repl::new(StackOptions::default());
+        repl.mode = Mode::Wottasquare;
+        execute(&mut repl, "[5][65]").unwrap();
+        execute(&mut repl, "[12:DUP]").unwrap();
+        assert_eq!(repl.stack(), &[65, 65]);
+        assert!(repl.undo());
+        assert_eq!(repl.stack(), &[65]);
+        assert_eq!(execute(&mut repl, "[9:OUTPUT]").unwrap(), b"A");
+        assert!(repl.undo());
+        assert!(repl.undo());
+        assert!(!repl.undo());
+    }
+
+    #[test]
+    fn test_failed_line_keeps_stack() {
+        let mut repl = Repl::new(StackOptions::default());
+        repl.mode = Mode::Wottasquare;
+        execute(&mut repl, "[5][1]").unwrap();
+        assert!(execute(&mut repl, "[7:ADD]").is_err());
+        assert_eq!(repl.stack(), &[1]);
+    }
+}
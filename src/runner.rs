@@ -1,12 +1,16 @@
 // Needed because of https://github.com/Geal/nom/issues/345
 
+use crate::comments::{self, Comment, Delimiters};
 use crate::common::{self, word_match};
-use nom::digit;
+use crate::error::{Error, Result};
+use nom::{digit, IResult, Needed};
 use num_traits::FromPrimitive;
-use std::io::{self, Read};
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufWriter, Write};
 use std::str::{self, FromStr};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Word {
     pub word: String,
     pub score: u8,
@@ -22,6 +26,47 @@ impl Word {
 enum RawWord {
     Junk,
     Word(Word),
+    Comment(String),
+    /// Source text between words, along with what it says if it's a comment
+    Spacing(String, Option<String>),
+}
+
+/// A program's words, and the comments between them
+#[derive(Debug, Default, PartialEq)]
+pub struct Program {
+    pub words: Vec<Word>,
+    pub comments: Vec<Comment>,
+    /// The source text before each word and after the last one, when the words are the original ones
+    /// from the source, or empty if not known
+    pub spacing: Vec<String>,
+}
+
+impl Program {
+    /// Rebuilds the exact Beatnik source a program was read from
+    pub fn source(self: &Program) -> Result<String> {
+        if self.spacing.len() != self.words.len() + 1 {
+            return Err(Error::Generate(String::from(
+                "Program doesn't have its original text, so can't be turned back into Beatnik",
+            )));
+        }
+        let mut out = String::new();
+        for (index, word) in self.words.iter().enumerate() {
+            let word_score = score(&word.word.to_lowercase());
+            if word_score != word.score {
+                return Err(Error::Generate(format!(
+                    "Word {} is '{}', which scores {} rather than {}",
+                    index + 1,
+                    word.word,
+                    word_score,
+                    word.score
+                )));
+            }
+            out += &self.spacing[index];
+            out += &word.word;
+        }
+        out += &self.spacing[self.words.len()];
+        Ok(out)
+    }
 }
 
 pub fn score(word: &str) -> u8 {
@@ -39,7 +84,7 @@ pub fn score(word: &str) -> u8 {
             _ => 0,
         }
     }
-    return result;
+    result
 }
 
 named!(get_word<&str, RawWord>,
@@ -48,7 +93,7 @@ named!(get_word<&str, RawWord>,
                 let mut word = String::from(begin);
                 word += rest;
                 let sc = score(&word.to_lowercase());
-                RawWord::Word(Word{word:word, score:sc})
+                RawWord::Word(Word{word, score:sc})
             }
         } |
         take_s!( 1 )  => { |_| RawWord::Junk }
@@ -56,7 +101,7 @@ named!(get_word<&str, RawWord>,
 );
 
 named!(get_wotta<&str, RawWord>,
-    map!(tuple!(
+    map_res!(tuple!(
         tag_s!("["),
         digit,
         opt!(
@@ -68,11 +113,139 @@ named!(get_wotta<&str, RawWord>,
         tag_s!("]"),
         opt!(tag_s!("\n"))
     ), |(_, raw_score, comment_opt, _, _)|{
-        let word = String::from((comment_opt as Option<(&str, &str)>).unwrap().1);
-        RawWord::Word(Word{word: word, score: u8::from_str(raw_score).unwrap()})
+        let word = String::from((comment_opt as Option<(&str, &str)>).map_or("", |c| c.1));
+        // Scores over 255 don't fit, and fail the parse
+        u8::from_str(raw_score).map(|score| RawWord::Word(Word{word, score}))
         })
 );
 
+/// Beatnik words, skipping over comments if the source starts with front matter turning them on
+fn beatnik_parser() -> impl FnMut(&str) -> IResult<&str, RawWord> {
+    let mut delimiters: Option<Delimiters> = None;
+    let mut at_start = true;
+    move |input: &str| {
+        if at_start {
+            at_start = false;
+            if let Some((found, rest)) = comments::front_matter(input) {
+                delimiters = Some(found);
+                return IResult::Done(rest, RawWord::Junk);
+            }
+        }
+        if let Some(delimiters) = &delimiters {
+            if let Some(body) = input.strip_prefix(delimiters.open.as_str()) {
+                return match body.find(&delimiters.close) {
+                    Some(end) => IResult::Done(
+                        &body[end + delimiters.close.len()..],
+                        RawWord::Comment(body[..end].trim().to_string()),
+                    ),
+                    // The comment might finish in text we haven't got yet
+                    None => IResult::Incomplete(Needed::Unknown),
+                };
+            }
+        }
+        get_word(input)
+    }
+}
+
+/// Wraps a Beatnik parser to keep everything it skips, so the source can be rebuilt exactly
+fn spaced_parser(
+    mut parser: impl FnMut(&str) -> IResult<&str, RawWord>,
+) -> impl FnMut(&str) -> IResult<&str, RawWord> {
+    move |input: &str| match parser(input) {
+        IResult::Done(rest, RawWord::Junk) => IResult::Done(
+            rest,
+            RawWord::Spacing(input[..input.len() - rest.len()].to_string(), None),
+        ),
+        IResult::Done(rest, RawWord::Comment(text)) => IResult::Done(
+            rest,
+            RawWord::Spacing(input[..input.len() - rest.len()].to_string(), Some(text)),
+        ),
+        other => other,
+    }
+}
+
+/// Text quoted as a JSON string, which keeps it on one line
+fn quote(text: &str) -> String {
+    serde_json::Value::from(text).to_string()
+}
+
+/// The quoted text a line starts with, and what's after that line
+fn quoted_line(input: &str) -> Option<(String, &str)> {
+    let line_end = input.find('\n').map_or(input.len(), |end| end + 1);
+    let text = serde_json::from_str(input[..line_end].trim()).ok()?;
+    Some((text, &input[line_end..]))
+}
+
+/// Wottasquare words, along with comments on lines starting with ';' and blank lines. Dumps of Beatnik
+/// also have each word's original text after it, and the text between words on lines of their own, both
+/// quoted.
+fn wotta_parser(input: &str) -> IResult<&str, RawWord> {
+    let line_end = input.find('\n').map_or(input.len(), |end| end + 1);
+    let line = &input[..line_end];
+    if let Some(comment) = line.strip_prefix(';') {
+        IResult::Done(&input[line_end..], RawWord::Comment(comment.trim().to_string()))
+    } else if line.trim().is_empty() {
+        IResult::Done(&input[line_end..], RawWord::Junk)
+    } else if let Some((text, rest)) = quoted_line(input).filter(|_| line.starts_with('"')) {
+        IResult::Done(rest, RawWord::Spacing(text, None))
+    } else {
+        match get_wotta(input) {
+            IResult::Done(rest, RawWord::Word(mut word)) => {
+                match quoted_line(rest).filter(|_| rest.starts_with(' ')) {
+                    Some((original, rest)) => {
+                        word.word = original;
+                        IResult::Done(rest, RawWord::Word(word))
+                    }
+                    None => IResult::Done(rest, RawWord::Word(word)),
+                }
+            }
+            other => other,
+        }
+    }
+}
+
+fn program_filter(word: RawWord) -> Option<RawWord> {
+    match word {
+        RawWord::Junk => None,
+        other => Some(other),
+    }
+}
+
+/// Gathers a program's words and comments, and the text between the words if it's `spaced` or turns out
+/// to be
+fn collect_program(items: impl Iterator<Item = Result<RawWord>>, mut spaced: bool) -> Result<Program> {
+    let mut program = Program::default();
+    let mut spacing = String::new();
+    for item in items {
+        let comment = match item? {
+            RawWord::Word(word) => {
+                program.spacing.push(std::mem::take(&mut spacing));
+                program.words.push(word);
+                None
+            }
+            RawWord::Comment(text) => Some(text),
+            RawWord::Spacing(text, comment) => {
+                spaced = true;
+                spacing += &text;
+                comment
+            }
+            RawWord::Junk => None,
+        };
+        if let Some(text) = comment {
+            program.comments.push(Comment {
+                position: program.words.len(),
+                text,
+            });
+        }
+    }
+    if spaced {
+        program.spacing.push(spacing);
+    } else {
+        program.spacing.clear();
+    }
+    Ok(program)
+}
+
 fn word_filter(word: RawWord) -> Option<Word> {
     if let RawWord::Word(x) = word {
         Some(x)
@@ -81,28 +254,94 @@ fn word_filter(word: RawWord) -> Option<Word> {
     }
 }
 
-pub fn get_words_fn(filename: &str) -> Result<Vec<Word>, io::Error> {
-    return common::get_words_core_fn(filename, get_word, word_filter);
+/// Words parsed lazily from a reader, optionally replacing invalid UTF-8 rather than failing. `name` is
+/// only used in messages.
+pub fn read_words<'a>(
+    reader: Box<dyn BufRead + 'a>,
+    name: &str,
+    lossy: bool,
+) -> impl Iterator<Item = Result<Word>> + 'a {
+    common::Stream::new(reader, name, beatnik_parser(), word_filter, lossy)
 }
 
-#[cfg(test)]
-pub fn get_words(buffer: &str) -> Result<Vec<Word>, io::Error> {
-    return common::get_words_core(buffer, get_word, word_filter);
+/// Like read_words, but for Wottasquare
+pub fn read_wottas<'a>(
+    reader: Box<dyn BufRead + 'a>,
+    name: &str,
+    lossy: bool,
+) -> impl Iterator<Item = Result<Word>> + 'a {
+    common::Stream::new(reader, name, wotta_parser, word_filter, lossy)
+}
+
+/// Like read_words, but keeping comments and the text between words
+pub fn read_words_with_comments<'a>(
+    reader: Box<dyn BufRead + 'a>,
+    name: &str,
+    lossy: bool,
+) -> Result<Program> {
+    collect_program(
+        common::Stream::new(
+            reader,
+            name,
+            spaced_parser(beatnik_parser()),
+            program_filter,
+            lossy,
+        ),
+        true,
+    )
 }
 
-pub fn get_wottas_fn(filename: &str) -> Result<Vec<Word>, io::Error> {
-    return common::get_words_core_fn(filename, get_wotta, word_filter);
+/// Like read_wottas, but keeping comments, and the original text if it's a dump of Beatnik
+pub fn read_wottas_with_comments<'a>(
+    reader: Box<dyn BufRead + 'a>,
+    name: &str,
+    lossy: bool,
+) -> Result<Program> {
+    collect_program(
+        common::Stream::new(reader, name, wotta_parser, program_filter, lossy),
+        false,
+    )
 }
 
-#[cfg(test)]
-pub fn get_wottas(buffer: &str) -> Result<Vec<Word>, io::Error> {
-    return common::get_words_core(buffer, get_wotta, word_filter);
+pub fn get_words_fn(filename: &str) -> Result<Vec<Word>> {
+    common::get_words_core_fn(filename, beatnik_parser(), word_filter)
+}
+
+pub fn get_words(buffer: &str) -> Result<Vec<Word>> {
+    common::get_words_core(buffer, beatnik_parser(), word_filter)
+}
+
+/// Byte offset of each word within some Beatnik source
+pub(crate) fn word_offsets(document: &str) -> Result<Vec<usize>> {
+    let mut parser = beatnik_parser();
+    let mut offsets = Vec::new();
+    let mut remaining = document;
+    while !remaining.is_empty() {
+        match parser(remaining) {
+            IResult::Done(rest, word) => {
+                if let RawWord::Word(_) = word {
+                    offsets.push(document.len() - remaining.len());
+                }
+                remaining = rest;
+            }
+            _ => return Err(Error::Parse(format!("Can't find the words in: {}", remaining))),
+        }
+    }
+    Ok(offsets)
+}
+
+pub fn get_wottas_fn(filename: &str) -> Result<Vec<Word>> {
+    common::get_words_core_fn(filename, wotta_parser, word_filter)
+}
+
+pub fn get_wottas(buffer: &str) -> Result<Vec<Word>> {
+    common::get_words_core(buffer, wotta_parser, word_filter)
 }
 
 enum_from_primitive! {
     #[allow(non_camel_case_types)]
     #[derive(Debug, PartialEq)]
-    enum Command {
+    pub enum Command {
         PUSH = 5,
         DISCARD = 6,
         ADD = 7,
@@ -120,125 +359,717 @@ enum_from_primitive! {
     }
 }
 
-fn action(act: u8) -> Command {
+pub fn action(act: u8) -> Command {
     match Command::from_u8(act) {
         Some(val) => val,
         None => Command::NOP,
     }
 }
 
-fn get_more_input() -> String {
-    let mut buffer = String::new();
-    io::stdin().read_to_string(&mut buffer).unwrap();
-    buffer
+/// Commands that read the next word as their operand, rather than running it
+pub(crate) fn takes_operand(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::PUSH
+            | Command::SKIP_AHEAD_ZERO
+            | Command::SKIP_AHEAD_NONZERO
+            | Command::SKIP_BACK_ZERO
+            | Command::SKIP_BACK_NONZERO
+    )
 }
 
-pub fn run_beatnik(words: &[Word]) {
-    let mut stack: Vec<u8> = Vec::new();
-    let mut pc: usize = 0;
-    let mut input = String::new();
-    loop {
-        debug!(
-            "'{}' = {} ({:?})",
-            words[pc].word,
-            words[pc].score,
-            action(words[pc].score)
-        );
+/// Whether a word with this score does nothing when executed
+pub fn is_nop(score: u8) -> bool {
+    action(score) == Command::NOP
+}
+
+/// For each position in a program (before each word, and after the last one) whether a no-op word can be
+/// inserted there without it being read as an operand or changing where a skip lands. Programs are decoded
+/// linearly, so every PUSH or SKIP is assumed to be followed by its operand.
+pub fn nop_gaps(words: &[Word]) -> Vec<bool> {
+    let mut gaps = vec![true; words.len() + 1];
+    let mut forbid = |from: usize, to: usize| {
+        for gap in gaps.iter_mut().take(to + 1).skip(from) {
+            *gap = false;
+        }
+    };
+    let distance = |pc: usize| words.get(pc + 1).map_or(0, |w| w.score as usize);
+    let mut pc = 0;
+    while pc < words.len() {
         match action(words[pc].score) {
             Command::PUSH => {
+                forbid(pc + 1, pc + 1);
                 pc += 1;
-                debug!("Pushing {} to stack", words[pc].score);
-                stack.push(words[pc].score);
             }
-            Command::DISCARD => {
-                stack.pop().expect("stack value");
+            Command::SKIP_AHEAD_ZERO | Command::SKIP_AHEAD_NONZERO => {
+                forbid(pc + 1, pc + 1);
+                forbid(pc + 2, cmp::min(pc + 1 + distance(pc), words.len() - 1));
+                pc += 1;
             }
-            Command::ADD => {
-                let x = stack.pop().expect("first value");
-                let y = stack.pop().expect("second value");
-                stack.push(x.wrapping_add(y));
+            Command::SKIP_BACK_ZERO | Command::SKIP_BACK_NONZERO => {
+                forbid(pc + 1, pc + 1);
+                forbid((pc + 2).saturating_sub(distance(pc)), pc);
+                pc += 1;
             }
-            Command::INPUT => {
-                if input.len() == 0 {
-                    input += &get_more_input();
-                }
-                let new_input = input.remove(0);
-                let mut b = [0; 1];
-                new_input.encode_utf8(&mut b);
-                stack.push(b[0]);
+            _ => {}
+        }
+        pc += 1;
+    }
+    gaps
+}
+
+/// What INPUT does once there are no more bytes to read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EofBehaviour {
+    /// Push 0 onto the stack
+    Zero,
+    /// Push 255 onto the stack
+    Max,
+    /// Stop the program
+    Halt,
+}
+
+impl FromStr for EofBehaviour {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<EofBehaviour, String> {
+        match s {
+            "zero" => Ok(EofBehaviour::Zero),
+            "max" => Ok(EofBehaviour::Max),
+            "halt" => Ok(EofBehaviour::Halt),
+            _ => Err(format!("Unknown EOF behaviour '{}'", s)),
+        }
+    }
+}
+
+/// Byte source for the INPUT command
+pub struct Input<'a> {
+    reader: Box<dyn BufRead + 'a>,
+    buffer: VecDeque<u8>,
+    eof: EofBehaviour,
+    line_buffered: bool,
+    finished: bool,
+}
+
+impl<'a> Input<'a> {
+    pub fn new(reader: Box<dyn BufRead + 'a>, eof: EofBehaviour, line_buffered: bool) -> Input<'a> {
+        Input {
+            reader,
+            buffer: VecDeque::new(),
+            eof,
+            line_buffered,
+            finished: false,
+        }
+    }
+
+    pub fn stdin(eof: EofBehaviour, line_buffered: bool) -> Input<'a> {
+        Input::new(Box::new(io::stdin().lock()), eof, line_buffered)
+    }
+
+    fn fill(self: &mut Input<'a>) -> io::Result<()> {
+        let mut chunk = Vec::new();
+        let read = if self.line_buffered {
+            self.reader.read_until(b'\n', &mut chunk)?
+        } else {
+            self.reader.read_to_end(&mut chunk)?
+        };
+        if read == 0 {
+            self.finished = true;
+        }
+        self.buffer.extend(chunk);
+        Ok(())
+    }
+
+    /// Next whole line, for the REPL which shares its input stream with INPUT
+    pub fn read_line(self: &mut Input<'a>) -> io::Result<Option<String>> {
+        let mut line = Vec::new();
+        while let Some(byte) = self.buffer.pop_front() {
+            line.push(byte);
+            if byte == b'\n' {
+                return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
             }
-            Command::OUTPUT => print!("{}", stack.pop().expect("character on stack") as char),
-            Command::SUBTRACT => {
-                let x = stack.pop().expect("first value");
-                let y = stack.pop().expect("second value");
-                stack.push(y.wrapping_sub(x));
+        }
+        if !self.finished && self.reader.read_until(b'\n', &mut line)? == 0 {
+            self.finished = true;
+        }
+        if line.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+        }
+    }
+
+    /// Next value for INPUT to push, or None if the program should halt
+    fn next_byte(self: &mut Input<'a>) -> io::Result<Option<u8>> {
+        while self.buffer.is_empty() && !self.finished {
+            self.fill()?;
+        }
+        Ok(match self.buffer.pop_front() {
+            Some(byte) => Some(byte),
+            None => match self.eof {
+                EofBehaviour::Zero => Some(0),
+                EofBehaviour::Max => Some(255),
+                EofBehaviour::Halt => None,
+            },
+        })
+    }
+}
+
+/// Byte sink for the OUTPUT command
+pub struct Output<'a> {
+    writer: Box<dyn Write + 'a>,
+    interactive: bool,
+}
+
+impl<'a> Output<'a> {
+    pub fn new(writer: Box<dyn Write + 'a>, interactive: bool) -> Output<'a> {
+        Output { writer, interactive }
+    }
+
+    /// Buffered stdout, unless interactive in which case every byte is flushed immediately
+    pub fn stdout(interactive: bool) -> Output<'a> {
+        let stdout = io::stdout().lock();
+        if interactive {
+            Output::new(Box::new(stdout), true)
+        } else {
+            Output::new(Box::new(BufWriter::new(stdout)), false)
+        }
+    }
+
+    fn write_byte(self: &mut Output<'a>, byte: u8) -> io::Result<()> {
+        self.writer.write_all(&[byte])?;
+        if self.interactive {
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(self: &mut Output<'a>) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Width of each stack cell. Beatnik proper uses bytes, the rest are an extension dialect.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CellWidth {
+    U8,
+    U16,
+    U32,
+    I64,
+}
+
+impl FromStr for CellWidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<CellWidth, String> {
+        match s {
+            "u8" => Ok(CellWidth::U8),
+            "u16" => Ok(CellWidth::U16),
+            "u32" => Ok(CellWidth::U32),
+            "i64" => Ok(CellWidth::I64),
+            _ => Err(format!("Unknown cell width '{}'", s)),
+        }
+    }
+}
+
+impl CellWidth {
+    fn min(self: CellWidth) -> i128 {
+        match self {
+            CellWidth::I64 => i128::from(i64::MIN),
+            _ => 0,
+        }
+    }
+
+    fn max(self: CellWidth) -> i128 {
+        match self {
+            CellWidth::U8 => i128::from(u8::MAX),
+            CellWidth::U16 => i128::from(u16::MAX),
+            CellWidth::U32 => i128::from(u32::MAX),
+            CellWidth::I64 => i128::from(i64::MAX),
+        }
+    }
+
+    fn wrap(self: CellWidth, value: i128) -> i64 {
+        match self {
+            CellWidth::I64 => value as i64,
+            _ => value.rem_euclid(self.max() + 1) as i64,
+        }
+    }
+}
+
+/// What ADD and SUBTRACT do when the result doesn't fit in a cell
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arithmetic {
+    Wrapping,
+    Checked,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StackOptions {
+    /// Deepest the stack may get before it's an error, to catch runaway PUSH loops
+    pub max_depth: Option<usize>,
+    pub cell: CellWidth,
+    pub arithmetic: Arithmetic,
+}
+
+impl Default for StackOptions {
+    fn default() -> StackOptions {
+        StackOptions {
+            max_depth: None,
+            cell: CellWidth::U8,
+            arithmetic: Arithmetic::Wrapping,
+        }
+    }
+}
+
+/// Interpreter state, kept apart from the program so the REPL can run it a line at a time
+#[derive(Debug, Clone, Default)]
+pub struct Machine {
+    pub stack: Vec<i64>,
+    options: StackOptions,
+    /// Most words to run before it's an error, to catch programs that never stop
+    pub max_steps: Option<usize>,
+}
+
+/// Start of the error message when a program runs for more than `max_steps`
+pub(crate) const STEP_LIMIT: &str = "Step limit";
+
+fn runtime_error(pc: usize, msg: &str) -> Error {
+    Error::Runtime(format!("{} at word {}", msg, pc + 1))
+}
+
+impl Machine {
+    pub fn with_options(options: StackOptions) -> Machine {
+        Machine {
+            stack: Vec::new(),
+            options,
+            max_steps: None,
+        }
+    }
+
+    fn pop(self: &mut Machine, pc: usize) -> Result<i64> {
+        self.stack
+            .pop()
+            .ok_or_else(|| runtime_error(pc, "Stack underflow"))
+    }
+
+    fn push(self: &mut Machine, pc: usize, value: i64) -> Result<()> {
+        if let Some(max_depth) = self.options.max_depth {
+            if self.stack.len() >= max_depth {
+                return Err(runtime_error(
+                    pc,
+                    &format!("Stack overflow (limit {})", max_depth),
+                ));
             }
-            Command::SWAP => {
-                let x = stack.pop().expect("first value");
-                let y = stack.pop().expect("second value");
-                stack.push(x);
-                stack.push(y);
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /// Fits the result of ADD or SUBTRACT into a cell
+    fn arithmetic(self: &Machine, pc: usize, value: i128) -> Result<i64> {
+        let cell = self.options.cell;
+        match self.options.arithmetic {
+            Arithmetic::Wrapping => Ok(cell.wrap(value)),
+            Arithmetic::Checked => {
+                if value < cell.min() || value > cell.max() {
+                    Err(runtime_error(
+                        pc,
+                        &format!("Arithmetic overflow ({} in {:?})", value, cell),
+                    ))
+                } else {
+                    Ok(value as i64)
+                }
             }
-            Command::DUP => {
-                let x = stack.pop().expect("first value");
-                stack.push(x);
-                stack.push(x);
+        }
+    }
+
+    pub fn run(self: &mut Machine, words: &[Word], input: &mut Input, output: &mut Output) -> Result<()> {
+        let operand = |pc: usize| {
+            words
+                .get(pc + 1)
+                .map(|w| w.score as usize)
+                .ok_or_else(|| runtime_error(pc, "Missing operand"))
+        };
+        let mut pc: usize = 0;
+        let mut steps = 0;
+        while pc < words.len() {
+            steps += 1;
+            if let Some(max_steps) = self.max_steps.filter(|max_steps| steps > *max_steps) {
+                return Err(runtime_error(
+                    pc,
+                    &format!("{} ({}) reached", STEP_LIMIT, max_steps),
+                ));
             }
-            Command::SKIP_AHEAD_NONZERO => {
-                let check = stack.pop().expect("check value");
-                pc += 1;
-                if check != 0 {
-                    pc += words[pc].score as usize;
+            debug!(
+                "'{}' = {} ({:?})",
+                words[pc].word,
+                words[pc].score,
+                action(words[pc].score)
+            );
+            match action(words[pc].score) {
+                Command::PUSH => {
+                    let value = operand(pc)? as i64;
+                    debug!("Pushing {} to stack", value);
+                    self.push(pc, value)?;
+                    pc += 1;
                 }
-            }
-            Command::SKIP_AHEAD_ZERO => {
-                let check = stack.pop().expect("check value");
-                pc += 1;
-                if check == 0 {
-                    pc += words[pc].score as usize;
+                Command::DISCARD => {
+                    self.pop(pc)?;
                 }
-            }
-            Command::SKIP_BACK_ZERO => {
-                let check = stack.pop().expect("check value");
-                if check == 0 {
-                    pc -= words[pc + 1].score as usize;
+                Command::ADD => {
+                    let x = self.pop(pc)?;
+                    let y = self.pop(pc)?;
+                    let sum = self.arithmetic(pc, i128::from(x) + i128::from(y))?;
+                    self.stack.push(sum);
                 }
-            }
-            Command::SKIP_BACK_NONZERO => {
-                let check = stack.pop().expect("check value");
-                if check != 0 {
-                    pc -= words[pc + 1].score as usize;
+                Command::INPUT => match input.next_byte()? {
+                    Some(byte) => self.push(pc, i64::from(byte))?,
+                    None => {
+                        debug!("Halting at end of input");
+                        break;
+                    }
+                },
+                Command::OUTPUT => {
+                    let x = self.pop(pc)?;
+                    // Wider cells output just their low byte
+                    output.write_byte(x as u8)?;
+                }
+                Command::SUBTRACT => {
+                    let x = self.pop(pc)?;
+                    let y = self.pop(pc)?;
+                    let difference = self.arithmetic(pc, i128::from(y) - i128::from(x))?;
+                    self.stack.push(difference);
+                }
+                Command::SWAP => {
+                    let x = self.pop(pc)?;
+                    let y = self.pop(pc)?;
+                    self.stack.push(x);
+                    self.stack.push(y);
+                }
+                Command::DUP => {
+                    let x = self.pop(pc)?;
+                    self.stack.push(x);
+                    self.push(pc, x)?;
+                }
+                Command::SKIP_AHEAD_NONZERO => {
+                    let check = self.pop(pc)?;
+                    let distance = operand(pc)?;
+                    pc += 1;
+                    if check != 0 {
+                        pc += distance;
+                    }
+                }
+                Command::SKIP_AHEAD_ZERO => {
+                    let check = self.pop(pc)?;
+                    let distance = operand(pc)?;
+                    pc += 1;
+                    if check == 0 {
+                        pc += distance;
+                    }
                 }
+                Command::SKIP_BACK_ZERO => {
+                    let check = self.pop(pc)?;
+                    let distance = operand(pc)?;
+                    if check == 0 {
+                        pc = pc
+                            .checked_sub(distance)
+                            .ok_or_else(|| runtime_error(pc, "Skipped back past the start"))?;
+                    }
+                }
+                Command::SKIP_BACK_NONZERO => {
+                    let check = self.pop(pc)?;
+                    let distance = operand(pc)?;
+                    if check != 0 {
+                        pc = pc
+                            .checked_sub(distance)
+                            .ok_or_else(|| runtime_error(pc, "Skipped back past the start"))?;
+                    }
+                }
+                Command::STOP => break,
+                Command::NOP => {}
             }
-            Command::STOP => break,
-            Command::NOP => {}
-        }
-        pc += 1;
-        if pc == words.len() {
-            break;
+            pc += 1;
         }
+        debug!("Stack: {:?}", self.stack);
+        Ok(output.flush()?)
     }
-    debug!("Stack: {:?}", stack);
 }
 
-pub fn output_wottasquare(words: Vec<Word>) {
-    for word in words {
-        println!("[{}:{:?}]", word.score, action(word.score));
+/// Runs a program with plain byte-sized Beatnik semantics
+pub fn run_beatnik(words: &[Word], input: &mut Input, output: &mut Output) -> Result<()> {
+    Machine::default().run(words, input, output)
+}
+
+/// Writes a program out in Wottasquare form, one word per line with comments on lines starting with ';'.
+/// If the program has its original text, each word is followed by its quoted text, and the text between
+/// words goes on lines of its own, so the source can be rebuilt exactly.
+pub fn write_wottasquare(program: &Program, out: &mut dyn Write) -> Result<()> {
+    let words = &program.words;
+    let mut comments = program.comments.iter().peekable();
+    for position in 0..=words.len() {
+        while let Some(comment) = comments.next_if(|comment| comment.position <= position) {
+            for line in comment.text.lines() {
+                writeln!(out, "; {}", line.trim())?;
+            }
+        }
+        // The text at the end is always written, even if empty, to show the dump has the original text
+        if let Some(spacing) = program.spacing.get(position) {
+            if !spacing.is_empty() || position == words.len() {
+                writeln!(out, "{}", quote(spacing))?;
+            }
+        }
+        if let Some(word) = words.get(position) {
+            if program.spacing.is_empty() {
+                writeln!(out, "[{}:{:?}]", word.score, action(word.score))?;
+            } else {
+                writeln!(
+                    out,
+                    "[{}:{:?}] {}",
+                    word.score,
+                    action(word.score),
+                    quote(&word.word)
+                )?;
+            }
+        }
     }
+    Ok(out.flush()?)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{get_words, get_wottas};
+    use super::{
+        get_words, get_wottas, nop_gaps, read_words, read_words_with_comments, read_wottas,
+        read_wottas_with_comments, run_beatnik, write_wottasquare, Arithmetic, CellWidth, EofBehaviour,
+        Input, Machine, Output, StackOptions, Word,
+    };
     use quickcheck::TestResult;
+    use std::io::{self, Write};
+
+    fn run_with_input(program: &str, input: &[u8], eof: EofBehaviour) -> Vec<u8> {
+        let words = get_wottas(program).unwrap();
+        let mut input = Input::new(Box::new(input), eof, false);
+        let mut output = Vec::new();
+        run_beatnik(&words, &mut input, &mut Output::new(Box::new(&mut output), false)).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_input_non_ascii() {
+        let cat = "[8:INPUT]\n[9:OUTPUT]\n[8:INPUT]\n[9:OUTPUT]\n";
+        assert_eq!(
+            run_with_input(cat, "é".as_bytes(), EofBehaviour::Zero),
+            "é".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_input_eof() {
+        let program = "[8:INPUT]\n[8:INPUT]\n[9:OUTPUT]\n[9:OUTPUT]\n";
+        assert_eq!(run_with_input(program, b"a", EofBehaviour::Zero), vec![0, b'a']);
+        assert_eq!(run_with_input(program, b"a", EofBehaviour::Max), vec![255, b'a']);
+        assert_eq!(
+            run_with_input(program, b"a", EofBehaviour::Halt),
+            Vec::<u8>::new()
+        );
+    }
+
+    #[test]
+    fn test_line_buffered_input() {
+        let words = get_wottas("[8:INPUT]\n[8:INPUT]\n[8:INPUT]\n[9:OUTPUT]\n").unwrap();
+        let mut input = Input::new(Box::new(&b"a\nb\n"[..]), EofBehaviour::Halt, true);
+        let mut output = Vec::new();
+        run_beatnik(&words, &mut input, &mut Output::new(Box::new(&mut output), true)).unwrap();
+        assert_eq!(output, b"b");
+    }
+
+    /// Records what had been written each time the interpreter flushed
+    struct FlushLog<'a> {
+        pending: Vec<u8>,
+        flushed: &'a mut Vec<Vec<u8>>,
+    }
+
+    impl<'a> Write for FlushLog<'a> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.pending.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushed.push(self.pending.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_interactive_flushes_each_output() {
+        let words = get_wottas("[5:PUSH]\n[104:h]\n[9:OUTPUT]\n[5:PUSH]\n[105:i]\n[9:OUTPUT]\n").unwrap();
+        let mut input = Input::new(Box::new(&b""[..]), EofBehaviour::Zero, true);
+        let mut flushed = Vec::new();
+        let log = FlushLog {
+            pending: Vec::new(),
+            flushed: &mut flushed,
+        };
+        run_beatnik(&words, &mut input, &mut Output::new(Box::new(log), true)).unwrap();
+        assert_eq!(flushed, vec![b"h".to_vec(), b"hi".to_vec(), b"hi".to_vec()]);
+    }
 
     #[test]
     fn test_apostrophe() {
-        let ref word = get_words("she’s").unwrap()[0];
+        let word = &get_words("she’s").unwrap()[0];
         assert_eq!(word.score, 7);
     }
 
+    #[test]
+    fn test_streaming() {
+        let text = "Hello world\n".repeat(10_000);
+        let words: Vec<Word> = read_words(Box::new(text.as_bytes()), "test", false)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(words.len(), 20_000);
+        assert!(words.iter().all(|w| w.word == "Hello" || w.word == "world"));
+    }
+
+    #[test]
+    fn test_lossy_utf_8() {
+        let bytes = &b"Caf\xff ok\n"[..];
+        let err = read_words(Box::new(bytes), "test", false)
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(err.to_string().contains("Line 1 of test"), "{}", err);
+        let words: Vec<String> = read_words(Box::new(bytes), "test", true)
+            .map(|w| w.unwrap().word)
+            .collect();
+        assert_eq!(words, vec!["Caf", "ok"]);
+    }
+
+    #[test]
+    fn test_parse_error_line() {
+        let text = format!("{}[x]\n", "[5:PUSH]\n".repeat(3));
+        let err = read_wottas(Box::new(text.as_bytes()), "test", false)
+            .collect::<Result<Vec<Word>, _>>()
+            .unwrap_err();
+        assert!(err.to_string().contains("Line 4 of test"), "{}", err);
+        assert_eq!(err.exit_code(), crate::error::EXIT_PARSE);
+    }
+
+    #[test]
+    fn test_score_too_big() {
+        let err = get_wottas("[5:PUSH]\n[300]\n").unwrap_err();
+        assert!(err.to_string().contains("Line 2"), "{}", err);
+        assert_eq!(err.exit_code(), crate::error::EXIT_PARSE);
+        assert_eq!(get_wottas("[255]\n").unwrap()[0].score, 255);
+    }
+
+    #[test]
+    fn test_nop_gaps() {
+        let skip_ahead = get_wottas("[13:SKIP_AHEAD_ZERO]\n[2]\n[1]\n[1]\n[6:DISCARD]\n").unwrap();
+        assert_eq!(nop_gaps(&skip_ahead), vec![true, false, false, false, true, true]);
+        let skip_back = get_wottas("[1]\n[1]\n[15:SKIP_BACK_ZERO]\n[2]\n[1]\n").unwrap();
+        assert_eq!(nop_gaps(&skip_back), vec![true, true, false, false, true, true]);
+    }
+
+    fn run_with_options(program: &str, options: StackOptions) -> Result<Vec<i64>, String> {
+        let words = get_wottas(program).unwrap();
+        let mut input = Input::new(Box::new(&b""[..]), EofBehaviour::Zero, false);
+        let mut machine = Machine::with_options(options);
+        machine
+            .run(&words, &mut input, &mut Output::new(Box::new(io::sink()), false))
+            .map_err(|e| e.to_string())?;
+        Ok(machine.stack)
+    }
+
+    #[test]
+    fn test_stack_limit() {
+        let push_loop = "[1:NOP]\n[5:PUSH]\n[1]\n[12:DUP]\n[16:SKIP_BACK_NONZERO]\n[4]\n";
+        let options = StackOptions {
+            max_depth: Some(100),
+            ..StackOptions::default()
+        };
+        let err = run_with_options(push_loop, options).unwrap_err();
+        assert!(err.contains("Stack overflow"), "{}", err);
+    }
+
+    #[test]
+    fn test_cell_widths() {
+        let add = "[5:PUSH]\n[200]\n[5:PUSH]\n[100]\n[7:ADD]\n";
+        let subtract = "[5:PUSH]\n[1]\n[5:PUSH]\n[2]\n[10:SUBTRACT]\n";
+        let options = |cell, arithmetic| StackOptions {
+            max_depth: None,
+            cell,
+            arithmetic,
+        };
+        assert_eq!(run_with_options(add, StackOptions::default()), Ok(vec![44]));
+        assert_eq!(run_with_options(subtract, StackOptions::default()), Ok(vec![255]));
+        assert_eq!(
+            run_with_options(add, options(CellWidth::U16, Arithmetic::Wrapping)),
+            Ok(vec![300])
+        );
+        assert_eq!(
+            run_with_options(subtract, options(CellWidth::U32, Arithmetic::Wrapping)),
+            Ok(vec![4294967295])
+        );
+        assert_eq!(
+            run_with_options(subtract, options(CellWidth::I64, Arithmetic::Checked)),
+            Ok(vec![-1])
+        );
+        assert!(run_with_options(add, options(CellWidth::U8, Arithmetic::Checked)).is_err());
+        assert!(run_with_options(subtract, options(CellWidth::U16, Arithmetic::Checked)).is_err());
+    }
+
+    /// Dumps some Beatnik to Wottasquare, and gives what that turns back into
+    fn round_trip(source: &str) -> String {
+        let program = read_words_with_comments(Box::new(source.as_bytes()), "test", false).unwrap();
+        let mut dumped = Vec::new();
+        write_wottasquare(&program, &mut dumped).unwrap();
+        let reread = read_wottas_with_comments(Box::new(&dumped[..]), "dump", false).unwrap();
+        assert_eq!(reread.words, program.words);
+        assert_eq!(reread.comments, program.comments);
+        reread.source().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_examples() {
+        for entry in std::fs::read_dir("examples").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|extension| extension == "beatnik") {
+                let source = std::fs::read_to_string(&path).unwrap();
+                assert_eq!(round_trip(&source), source, "{}", path.display());
+            }
+        }
+        assert_eq!(round_trip("Hello"), "Hello");
+        assert_eq!(round_trip(""), "");
+    }
+
+    #[test]
+    fn test_restore_checks_scores() {
+        let dump = "[8:INPUT] \"Hello\"\n\", \"\n[6:DISCARD] \"aunts\"\n\"!\\n\"\n";
+        let program = read_wottas_with_comments(Box::new(dump.as_bytes()), "dump", false).unwrap();
+        let err = program.source().unwrap_err();
+        assert!(
+            err.to_string().contains("'aunts', which scores 5 rather than 6"),
+            "{}",
+            err
+        );
+        // Plain Wottasquare doesn't have the text
+        let plain = read_wottas_with_comments(Box::new(&b"[8:INPUT]\n"[..]), "plain", false).unwrap();
+        assert!(plain.source().is_err());
+    }
+
     quickcheck! {
+        fn round_trip_test(xs: String) -> TestResult {
+            if get_words(&xs).is_err() {
+                return TestResult::discard();
+            }
+            let after = round_trip(&xs);
+            if after == xs {
+                TestResult::passed()
+            } else {
+                TestResult::error(format!("{:?} became {:?}", xs, after))
+            }
+        }
+
         fn word_test(xs: String) -> TestResult {
             return match get_words(&xs) {
                 Ok(_) => TestResult::passed(),
@@ -250,7 +1081,7 @@ mod tests {
         }
 
         fn wotta_test(xs: String) -> TestResult {
-          if xs.len() == 0 {
+          if xs.is_empty() {
               return TestResult::discard();
           }
           if xs.find("]").is_some() {
@@ -0,0 +1,192 @@
+use crate::error::{Error, Result};
+use crate::markov::{at_sentence_start, made_up_word, MarkovInfo, Token};
+use crate::runner::{self, Word};
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Slot,
+}
+
+/// Splits a template into literal prose and `{...}` slots. `{{` and `}}` are literal braces.
+fn parse_template(template: &str) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                if !chars.any(|c| c == '}') {
+                    return Err(Error::Parse(String::from("Unclosed '{' in template")));
+                }
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(literal));
+                    literal = String::new();
+                }
+                segments.push(Segment::Slot);
+            }
+            _ => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    Ok(segments)
+}
+
+/// Walks prose words against a program, checking each one is either the next program word or a no-op
+/// that's safe to have there
+struct Matcher<'a> {
+    program: &'a [Word],
+    gaps: Vec<bool>,
+    next: usize,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(program: &'a [Word]) -> Matcher<'a> {
+        Matcher {
+            program,
+            gaps: runner::nop_gaps(program),
+            next: 0,
+        }
+    }
+
+    fn accept(self: &mut Matcher<'a>, word: &Word) -> Result<()> {
+        if self.next < self.program.len() && word.score == self.program[self.next].score {
+            self.next += 1;
+            Ok(())
+        } else if runner::is_nop(word.score) && self.gaps[self.next] {
+            Ok(())
+        } else {
+            Err(Error::Generate(format!(
+                "'{}' (score {}) would be run as {:?} where program word {} should be",
+                word.word,
+                word.score,
+                runner::action(word.score),
+                self.next + 1
+            )))
+        }
+    }
+
+    fn finish(self: &Matcher<'a>) -> Result<()> {
+        if self.next < self.program.len() {
+            Err(Error::Generate(format!(
+                "Only {} of {} program words are covered",
+                self.next,
+                self.program.len()
+            )))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Checks that a piece of prose runs as the program, allowing for no-ops where they're harmless
+pub fn check_prose(prose: &[Word], program: &[Word]) -> Result<()> {
+    let mut matcher = Matcher::new(program);
+    for word in prose {
+        matcher.accept(word)?;
+    }
+    matcher.finish()
+}
+
+/// Fills the slots in a template with words from the Markov model so that the whole text runs as the
+/// program. Literal words must either be no-ops or be exactly the program word due at that point.
+pub fn fill_template(template: &str, program: &[Word], markov: &MarkovInfo) -> Result<String> {
+    let mut matcher = Matcher::new(program);
+    let mut last = Token::Begin.string();
+    let mut out = String::new();
+    for segment in parse_template(template)? {
+        match segment {
+            Segment::Literal(text) => {
+                for word in runner::get_words(&text)? {
+                    matcher.accept(&word)?;
+                    last = word.word.to_lowercase();
+                }
+                out.push_str(&text);
+            }
+            Segment::Slot => {
+                let score = match program.get(matcher.next) {
+                    Some(word) => word.score,
+                    None => {
+                        return Err(Error::Generate(format!(
+                            "Template has more slots than the program's {} words",
+                            program.len()
+                        )))
+                    }
+                };
+                let word = match markov.get_word(&last, score) {
+                    Some(word) => word,
+                    None if score > 0 => made_up_word(score),
+                    None => return Err(Error::Generate(String::from("No word in the model has score 0"))),
+                };
+                let word = markov.recase(&word, at_sentence_start(&out));
+                matcher.next += 1;
+                out.push_str(&word);
+                last = word.to_lowercase();
+            }
+        }
+    }
+    matcher.finish()?;
+
+    // Slots butting up against literal text can merge into other words, so check the end result
+    check_prose(&runner::get_words(&out)?, program)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fill_template, parse_template, Segment};
+    use crate::markov;
+    use crate::runner;
+
+    fn model() -> markov::MarkovInfo {
+        markov::build_markov(markov::get_tokens("Ha. Trace the cat. Aha, no dog!").unwrap())
+    }
+
+    #[test]
+    fn test_parse_template() {
+        assert_eq!(
+            parse_template("a {cmd} {{b}} {}").unwrap(),
+            vec![
+                Segment::Literal(String::from("a ")),
+                Segment::Slot,
+                Segment::Literal(String::from(" {b} ")),
+                Segment::Slot
+            ]
+        );
+        assert!(parse_template("a {cmd").is_err());
+    }
+
+    #[test]
+    fn test_fill_template() {
+        // PUSH 5 PUSH 5 ADD, with "cat" supplying the first operand
+        let program = runner::get_wottas("[5:PUSH]\n[5]\n[5:PUSH]\n[5]\n[7:ADD]\n").unwrap();
+        let prose = fill_template("So {cmd} cat: I {cmd} {cmd}, a {cmd}.", &program, &model()).unwrap();
+        let scores: Vec<u8> = runner::get_words(&prose)
+            .unwrap()
+            .iter()
+            .map(|w| w.score)
+            .collect();
+        assert_eq!(scores, vec![2, 5, 5, 1, 5, 5, 1, 7]);
+    }
+
+    #[test]
+    fn test_literal_commands_rejected() {
+        let program = runner::get_wottas("[5:PUSH]\n[5]\n[9:OUTPUT]\n").unwrap();
+        // "So" is a no-op, but ends up as PUSH's operand
+        assert!(fill_template("{cmd} so {cmd} {cmd}", &program, &model()).is_err());
+        // "Trace" is ADD
+        assert!(fill_template("{cmd} {cmd} trace {cmd}", &program, &model()).is_err());
+        assert!(fill_template("{cmd} {cmd}", &program, &model()).is_err());
+        assert!(fill_template("{cmd} {cmd} {cmd} {cmd}", &program, &model()).is_err());
+    }
+}
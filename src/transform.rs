@@ -0,0 +1,402 @@
+use crate::error::{Error, Result};
+use crate::runner::{self, Command, Program, Word};
+use std::collections::BTreeSet;
+
+/// A skip in a program, with where it lands when taken as the index of the next word to run
+struct Skip {
+    command: usize,
+    target: usize,
+    forwards: bool,
+}
+
+/// Whether each word is read as an operand rather than run. Like runner::nop_gaps, this assumes programs
+/// are decoded linearly.
+pub(crate) fn operand_slots(words: &[Word]) -> Vec<bool> {
+    let mut slots = vec![false; words.len()];
+    let mut pc = 0;
+    while pc < words.len() {
+        match runner::action(words[pc].score) {
+            Command::PUSH
+            | Command::SKIP_AHEAD_ZERO
+            | Command::SKIP_AHEAD_NONZERO
+            | Command::SKIP_BACK_ZERO
+            | Command::SKIP_BACK_NONZERO => {
+                if pc + 1 < words.len() {
+                    slots[pc + 1] = true;
+                }
+                pc += 1;
+            }
+            _ => {}
+        }
+        pc += 1;
+    }
+    slots
+}
+
+fn transform_error(pc: usize, msg: &str) -> Error {
+    Error::Transform(format!("{} at word {}", msg, pc + 1))
+}
+
+fn skips(words: &[Word]) -> Result<Vec<Skip>> {
+    let slots = operand_slots(words);
+    let mut skips = Vec::new();
+    for (pc, word) in words.iter().enumerate() {
+        if slots[pc] || pc + 1 >= words.len() {
+            continue;
+        }
+        let distance = words[pc + 1].score as usize;
+        match runner::action(word.score) {
+            Command::SKIP_AHEAD_ZERO | Command::SKIP_AHEAD_NONZERO => skips.push(Skip {
+                command: pc,
+                target: pc + 2 + distance,
+                forwards: true,
+            }),
+            Command::SKIP_BACK_ZERO | Command::SKIP_BACK_NONZERO => {
+                if distance > pc {
+                    return Err(transform_error(pc, "Skip back goes past the start"));
+                }
+                skips.push(Skip {
+                    command: pc,
+                    target: pc + 1 - distance,
+                    forwards: false,
+                })
+            }
+            _ => {}
+        }
+    }
+    Ok(skips)
+}
+
+/// Indexes of the words in a program that are run as no-ops, rather than being operands
+pub fn nop_words(words: &[Word]) -> Vec<usize> {
+    let slots = operand_slots(words);
+    (0..words.len())
+        .filter(|&pc| !slots[pc] && runner::is_nop(words[pc].score))
+        .collect()
+}
+
+/// Rebuilds a program with words inserted and removed, changing skip operands so that every skip still
+/// lands on the same word as before
+fn rewrite(words: &[Word], inserts: &[(usize, Word)], removed: &[bool]) -> Result<Vec<Word>> {
+    let skips = skips(words)?;
+    let n = words.len();
+
+    // pos[i] is where old word i ends up, or the word after it if it's been removed
+    let mut pos = Vec::with_capacity(n + 1);
+    let mut out = Vec::new();
+    for i in 0..=n {
+        out.extend(
+            inserts
+                .iter()
+                .filter(|(gap, _)| *gap == i)
+                .map(|(_, word)| word.clone()),
+        );
+        pos.push(out.len());
+        if i < n && !removed[i] {
+            out.push(words[i].clone());
+        }
+    }
+    // Skipping ahead past the end just stops, so keep the overshoot as it was
+    let new_pos = |i: usize| if i > n { out.len() + i - n } else { pos[i] };
+
+    let mut operands = Vec::new();
+    for skip in skips {
+        let command = pos[skip.command];
+        let distance = if skip.forwards {
+            new_pos(skip.target) - command - 2
+        } else {
+            command + 1 - new_pos(skip.target)
+        };
+        if distance > 255 {
+            return Err(transform_error(
+                skip.command,
+                &format!("Skip would need a distance of {}", distance),
+            ));
+        }
+        // A skip back lands on the word after the one it goes back to, so it can never land on the first
+        if !skip.forwards && distance > command {
+            return Err(transform_error(skip.command, "Skip back would go past the start"));
+        }
+        let old = words[skip.command + 1].score;
+        // An untaken skip back runs its operand, so that mustn't start doing something else
+        if !skip.forwards && runner::action(distance as u8) != runner::action(old) {
+            return Err(transform_error(
+                skip.command,
+                &format!(
+                    "Skip back distance of {} would run as {:?} rather than {:?}",
+                    distance,
+                    runner::action(distance as u8),
+                    runner::action(old)
+                ),
+            ));
+        }
+        if distance as u8 != old {
+            operands.push((command + 1, distance as u8));
+        }
+    }
+    for (index, score) in operands {
+        out[index] = Word {
+            word: String::new(),
+            score,
+        };
+    }
+    Ok(out)
+}
+
+/// Puts no-op words into a program, each in front of the word at the given index (or at the end for the
+/// program's length), adjusting skips to allow for them. They can't go in front of an operand.
+pub fn insert_nops(words: &[Word], inserts: &[(usize, Word)]) -> Result<Vec<Word>> {
+    let slots = operand_slots(words);
+    for (gap, word) in inserts {
+        if *gap > words.len() {
+            return Err(transform_error(*gap, "Inserting past the end of the program"));
+        }
+        if *gap < words.len() && slots[*gap] {
+            return Err(transform_error(*gap, "Inserting in front of an operand"));
+        }
+        if !runner::is_nop(word.score) {
+            return Err(transform_error(
+                *gap,
+                &format!("Inserting '{}' which isn't a no-op", word.word),
+            ));
+        }
+    }
+    rewrite(words, inserts, &vec![false; words.len()])
+}
+
+/// Takes the no-op words at the given indexes out of a program, adjusting skips to allow for them
+pub fn remove_nops(words: &[Word], indexes: &[usize]) -> Result<Vec<Word>> {
+    let nops = nop_words(words);
+    let mut removed = vec![false; words.len()];
+    for index in indexes {
+        if !nops.contains(index) {
+            return Err(transform_error(*index, "Removing a word that isn't a no-op"));
+        }
+        removed[*index] = true;
+    }
+    rewrite(words, &[], &removed)
+}
+
+/// A pattern that does nothing, or less than it seems, starting at an instruction
+struct Peephole {
+    /// How many words it covers
+    length: usize,
+    removed: Vec<usize>,
+    /// A new score for a word that's kept
+    operand: Option<(usize, u8)>,
+}
+
+fn peephole(words: &[Word], pc: usize) -> Option<Peephole> {
+    let change = |length, removed, operand| {
+        Some(Peephole {
+            length,
+            removed,
+            operand,
+        })
+    };
+    let command = |index: usize| words.get(index).map(|word| runner::action(word.score));
+    match (command(pc), command(pc + 1), command(pc + 2), command(pc + 4)) {
+        (Some(Command::PUSH), _, Some(Command::DISCARD), _) => change(3, vec![pc, pc + 1, pc + 2], None),
+        (Some(Command::DUP), Some(Command::DISCARD), _, _)
+        | (Some(Command::SWAP), Some(Command::SWAP), _, _) => change(2, vec![pc, pc + 1], None),
+        (Some(Command::NOP), _, _, _) => change(1, vec![pc], None),
+        // Only folded when there's no wrapping, so it's the same for every cell width
+        (Some(Command::PUSH), _, Some(Command::PUSH), Some(Command::ADD)) => {
+            let sum = u16::from(words[pc + 1].score) + u16::from(words[pc + 3].score);
+            if sum <= 255 {
+                change(5, vec![pc + 2, pc + 3, pc + 4], Some((pc + 1, sum as u8)))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Shortens a program by taking out `PUSH x DISCARD`, `DUP DISCARD`, `SWAP SWAP` and no-ops, and folding
+/// `PUSH a PUSH b ADD` into one PUSH, adjusting skips to allow for it. Nothing a skip lands inside is
+/// changed, though code that would have failed with a stack underflow may no longer do so. Comments are
+/// kept, but the original text isn't.
+pub fn optimise(program: &Program) -> Result<Program> {
+    let mut words = program.words.clone();
+    let slots = operand_slots(&words);
+    for skip in skips(&words)? {
+        // Landing on a PUSH's operand runs it, and an untaken skip back runs its own operand, so either mustn't
+        // then read the next word as its operand
+        let landing = words.get(skip.target).filter(|_| slots[skip.target]);
+        if landing.is_some() && runner::action(words[skip.target - 1].score) != Command::PUSH {
+            return Err(transform_error(
+                skip.command,
+                "Skip lands on another skip's distance, which may change, so can't safely optimise",
+            ));
+        }
+        let untaken = words.get(skip.command + 1).filter(|_| !skip.forwards);
+        for word in landing.into_iter().chain(untaken) {
+            if runner::takes_operand(&runner::action(word.score)) {
+                return Err(transform_error(
+                    skip.command,
+                    &format!(
+                        "Skip runs an operand as {:?}, so can't safely optimise",
+                        runner::action(word.score)
+                    ),
+                ));
+            }
+        }
+    }
+    let mut comments = program.comments.clone();
+    'changes: loop {
+        let slots = operand_slots(&words);
+        let targets: BTreeSet<usize> = skips(&words)?.iter().map(|skip| skip.target).collect();
+        for pc in (0..words.len()).filter(|pc| !slots[*pc]) {
+            let change = match peephole(&words, pc) {
+                Some(change) => change,
+                None => continue,
+            };
+            if (pc + 1..pc + change.length).any(|index| targets.contains(&index)) {
+                continue;
+            }
+            let mut changed = words.clone();
+            if let Some((index, score)) = change.operand {
+                changed[index] = Word {
+                    word: String::new(),
+                    score,
+                };
+            }
+            let mut removed = vec![false; words.len()];
+            for index in &change.removed {
+                removed[*index] = true;
+            }
+            // Not every skip can allow for it, so leave this one be if not
+            if let Ok(optimised) = rewrite(&changed, &[], &removed) {
+                for comment in comments.iter_mut() {
+                    comment.position -= change
+                        .removed
+                        .iter()
+                        .filter(|index| **index < comment.position)
+                        .count();
+                }
+                words = optimised;
+                continue 'changes;
+            }
+        }
+        break;
+    }
+    Ok(Program {
+        words,
+        comments,
+        spacing: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{insert_nops, nop_words, optimise, remove_nops};
+    use crate::runner::{self, Input, Machine, Output, Program, Word};
+
+    fn nop() -> Word {
+        Word {
+            word: String::from("a"),
+            score: 1,
+        }
+    }
+
+    fn scores(words: &[Word]) -> Vec<u8> {
+        words.iter().map(|w| w.score).collect()
+    }
+
+    fn run(words: &[Word]) -> Vec<u8> {
+        let mut out = Vec::new();
+        {
+            let mut input = Input::new(Box::new(&b""[..]), runner::EofBehaviour::Zero, false);
+            let mut output = Output::new(Box::new(&mut out), false);
+            Machine::default().run(words, &mut input, &mut output).unwrap();
+        }
+        out
+    }
+
+    /// Counts down from 3, outputting each number, then skips over an OUTPUT of 9. The loop is padded
+    /// out so that the skip back's operand is a no-op when it's not taken.
+    fn program() -> Vec<Word> {
+        let mut wottas = String::from("[5:PUSH]\n[3]\n[12:DUP]\n[9:OUTPUT]\n[5:PUSH]\n[1]\n[10:SUBTRACT]\n");
+        wottas += &"[1]\n".repeat(11);
+        wottas +=
+            "[12:DUP]\n[16:SKIP_BACK_NONZERO]\n[18]\n[13:SKIP_AHEAD_ZERO]\n[3]\n[5:PUSH]\n[9]\n[9:OUTPUT]\n";
+        runner::get_wottas(&wottas).unwrap()
+    }
+
+    #[test]
+    fn test_insert_nops() {
+        let program = program();
+        assert_eq!(run(&program), vec![3, 2, 1]);
+        let padded = insert_nops(&program, &[(3, nop()), (23, nop()), (26, nop())]).unwrap();
+        assert_eq!(padded.len(), program.len() + 3);
+        assert_eq!(padded[21].score, 19);
+        assert_eq!(padded[23].score, 5);
+        assert_eq!(run(&padded), run(&program));
+    }
+
+    #[test]
+    fn test_remove_nops() {
+        let program = program();
+        let padded = insert_nops(&program, &[(2, nop()), (10, nop()), (23, nop())]).unwrap();
+        assert_eq!(run(&padded), run(&program));
+        let nops = nop_words(&padded);
+        assert!([2, 11, 25].iter().all(|i| nops.contains(i)), "{:?}", nops);
+        let stripped = remove_nops(&padded, &[2, 11, 25]).unwrap();
+        assert_eq!(scores(&stripped), scores(&program));
+    }
+
+    #[test]
+    fn test_optimise() {
+        // Says "Hi!" the long way round, then skips over a PUSH to a no-op
+        let wottas = "[5:PUSH]\n[40]\n[5:PUSH]\n[32]\n[7:ADD]\n[9:OUTPUT]\n\
+                      [5:PUSH]\n[105]\n[5:PUSH]\n[1]\n[11:SWAP]\n[11:SWAP]\n[6:DISCARD]\n[9:OUTPUT]\n\
+                      [5:PUSH]\n[33]\n[12:DUP]\n[6:DISCARD]\n[1]\n[20]\n[9:OUTPUT]\n\
+                      [5:PUSH]\n[0]\n[13:SKIP_AHEAD_ZERO]\n[2]\n[5:PUSH]\n[3]\n[1]\n";
+        let program = Program {
+            words: runner::get_wottas(wottas).unwrap(),
+            ..Program::default()
+        };
+        let optimised = optimise(&program).unwrap();
+        assert_eq!(run(&optimised.words), b"Hi!");
+        assert_eq!(
+            scores(&optimised.words),
+            vec![5, 72, 9, 5, 105, 9, 5, 33, 9, 5, 0, 13, 2, 5, 3]
+        );
+        // A skip landing part way through a pattern keeps it
+        let skipped =
+            runner::get_wottas("[5:PUSH]\n[0]\n[13:SKIP_AHEAD_ZERO]\n[1]\n[12:DUP]\n[6:DISCARD]\n").unwrap();
+        let program = Program {
+            words: skipped.clone(),
+            ..Program::default()
+        };
+        assert_eq!(scores(&optimise(&program).unwrap().words), scores(&skipped));
+        // Landing on an operand that's a PUSH would read the next word, which optimising can't allow for
+        let program = Program {
+            words: runner::get_wottas("[13:SKIP_AHEAD_ZERO]\n[1]\n[5:PUSH]\n[5]\n[1]\n").unwrap(),
+            ..Program::default()
+        };
+        assert!(optimise(&program).is_err());
+        // The skip back lands on the INPUT, so taking out the no-op in front of it would leave it nowhere
+        // to go back to. That no-op stays, rather than the whole optimisation failing.
+        let words = runner::get_wottas("[1]\n[8:INPUT]\n[16:SKIP_BACK_NONZERO]\n[2]\n").unwrap();
+        let program = Program {
+            words: words.clone(),
+            ..Program::default()
+        };
+        assert_eq!(scores(&optimise(&program).unwrap().words), scores(&words));
+    }
+
+    #[test]
+    fn test_rejected_changes() {
+        let program = program();
+        assert!(insert_nops(&program, &[(1, nop())]).is_err());
+        assert!(remove_nops(&program, &[1]).is_err());
+        assert!(insert_nops(&program, &vec![(23, nop()); 300]).is_err());
+        // Distance 18 to 17 would turn the untaken skip's operand from a no-op into STOP
+        assert!(remove_nops(&program, &[7]).is_err());
+        // Without the no-op, the skip back to the DUP would have to land on the first word
+        let words = runner::get_wottas("[1]\n[12:DUP]\n[16:SKIP_BACK_NONZERO]\n[2]\n").unwrap();
+        assert!(remove_nops(&words, &[0]).is_err());
+    }
+}